@@ -1,2 +1,5 @@
-pub use crate::trie::TNode;
+pub use crate::trie::{
+    InsertShape, InvalidCompactText, KeyExists, KeyNotFound, PrefOptions, StrictAddError,
+    StrictTrie, SubtrieHandle, TNode, TokenAddError, TOKEN_SEPARATOR,
+};
 mod trie;
@@ -1,42 +1,84 @@
-use std::collections::BTreeMap;
-use std::fmt::{self, Debug, Display};
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::{self, Display};
+use std::hash::{Hash, Hasher};
+use std::io::{self, BufRead, Read, Write};
+use std::mem;
+use std::ops::{Deref, Range};
+
+/// Separates the tokens of keys added with [`TNode::add_tokens`].
+pub const TOKEN_SEPARATOR: char = '\u{1f}';
 
 #[derive(Debug)]
-pub struct Leaf<'a, T> {
-    content: &'a Option<T>,
+pub struct Leaf<T, M = ()> {
+    content: Option<T>,
     is_terminal: bool,
+    version: u64,
+    max_version: u64,
+    metadata: M,
 }
 
 #[derive(Debug)]
-pub struct Node<'a, T: Debug + Display> {
-    content: &'a Option<T>,
-    children: BTreeMap<char, TNode<'a, T>>,
+pub struct Node<T, M = ()> {
+    content: Option<T>,
+    children: BTreeMap<char, TNode<T, M>>,
     is_terminal: bool,
+    version: u64,
+    max_version: u64,
+    metadata: M,
+    suffix_index: Option<Box<SuffixIndex>>,
+    // Keys in this subtree, this node's own included.
+    terminals: usize,
 }
 
+/// Reversed keys of a trie, as of `version`.
 #[derive(Debug)]
-pub enum TNode<'a, T: Display + Debug> {
-    Empty,
-    Leaf(Leaf<'a, T>),
-    Node(Node<'a, T>),
+struct SuffixIndex {
+    version: u64,
+    reversed: TNode<()>,
 }
 
-pub struct LongestPrefFlags {
-    is_terminal: bool,
-    full_match: bool,
+#[derive(Debug, Default)]
+pub enum TNode<T, M = ()> {
+    #[default]
+    Empty,
+    Leaf(Leaf<T, M>),
+    Node(Node<T, M>),
 }
 
-struct LongestPrefOpts {
-    must_be_terminal: bool,
-    must_match_fully: bool,
+/// Options for [`TNode::longest_prefix_opts`].
+///
+/// `must_match_fully` fails the lookup unless the whole query is a path in the trie,
+/// `must_be_terminal` only accepts prefixes that are keys (with `must_match_fully`, the whole
+/// query must be a key), and `prefer_terminal` returns the
+/// longest key on the path when the match ends on a non-terminal node, falling back to the
+/// longer non-terminal match when no key was passed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrefOptions {
+    pub must_be_terminal: bool,
+    pub must_match_fully: bool,
+    pub prefer_terminal: bool,
 }
 
-struct FindResults<'a, T: Display + Debug> {
-    node: Option<&'a TNode<'a, T>>,
-    prefix: String,
+/// What adding a key would do to the trie, see [`TNode::insert_shape`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InsertShape {
+    /// The key is already stored.
+    AlreadyPresent,
+    /// The key continues past the end of a path with no branches below it, sharing its
+    /// first `shared` chars with it.
+    ExtendsChain { shared: usize },
+    /// The key leaves an existing path after its first `at` chars, either with a new child
+    /// next to existing ones or by ending where longer keys go on.
+    NewBranch { at: usize },
 }
 
-type LongestPrefResult = Option<(Vec<char>, LongestPrefFlags)>;
+type SubtreeSig<'b, T> = (bool, Option<&'b T>, Vec<(char, usize)>);
+
+struct FindResults<'a, T, M> {
+    node: Option<&'a TNode<T, M>>,
+    prefix_len: usize,
+}
 
 #[derive(Debug, Clone)]
 pub struct KeyExists;
@@ -47,20 +89,20 @@ impl fmt::Display for KeyExists {
     }
 }
 
-impl<'a, T: Display + Debug> fmt::Display for TNode<'a, T> {
+impl<T: Display, M> fmt::Display for TNode<T, M> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match &self {
             TNode::Empty => {
                 write!(f, "(empty)")
             }
             TNode::Leaf(leaf) => {
-                if let Some(c) = leaf.content {
+                if let Some(c) = &leaf.content {
                     return write!(f, "({})", c);
                 }
                 Ok(())
             }
             TNode::Node(node) => {
-                if let Some(c) = node.content {
+                if let Some(c) = &node.content {
                     return write!(f, "({})", c);
                 }
                 Ok(())
@@ -69,6 +111,14 @@ impl<'a, T: Display + Debug> fmt::Display for TNode<'a, T> {
     }
 }
 
+/// Two tries are equal when they hold the same keys with the same contents; node versions
+/// and metadata are not compared.
+impl<T: PartialEq, M: Default> PartialEq for TNode<T, M> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries() == other.entries()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct KeyNotFound;
 
@@ -78,34 +128,226 @@ impl fmt::Display for KeyNotFound {
     }
 }
 
-impl<'a, T: Display + Debug> TNode<'a, T> {
-    fn to_leaf(&mut self) {
+#[derive(Debug, Clone)]
+pub struct InvalidCompactText {
+    pub line: usize,
+}
+
+impl fmt::Display for InvalidCompactText {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Invalid compact text at line {}", self.line)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum StrictAddError {
+    EmptyKey,
+    KeyExists,
+}
+
+impl fmt::Display for StrictAddError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StrictAddError::EmptyKey => write!(f, "Cannot add the empty key to a strict trie"),
+            StrictAddError::KeyExists => write!(f, "{}", KeyExists),
+        }
+    }
+}
+
+impl From<KeyExists> for StrictAddError {
+    fn from(_: KeyExists) -> Self {
+        StrictAddError::KeyExists
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum TokenAddError {
+    SeparatorInToken,
+    KeyExists,
+}
+
+impl fmt::Display for TokenAddError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenAddError::SeparatorInToken => write!(f, "Token contains TOKEN_SEPARATOR"),
+            TokenAddError::KeyExists => write!(f, "{}", KeyExists),
+        }
+    }
+}
+
+impl From<KeyExists> for TokenAddError {
+    fn from(_: KeyExists) -> Self {
+        TokenAddError::KeyExists
+    }
+}
+
+/// A trie that rejects the empty key. Lookups go through `Deref` to the inner [`TNode`].
+#[derive(Debug)]
+pub struct StrictTrie<T> {
+    trie: TNode<T>,
+}
+
+impl<T> StrictTrie<T> {
+    pub fn add(&mut self, s: &str, cont: Option<T>) -> Result<&TNode<T>, StrictAddError> {
+        if s.is_empty() {
+            return Err(StrictAddError::EmptyKey);
+        }
+        Ok(self.trie.add(s, cont)?)
+    }
+
+    pub fn into_inner(self) -> TNode<T> {
+        self.trie
+    }
+}
+
+impl<T> Deref for StrictTrie<T> {
+    type Target = TNode<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.trie
+    }
+}
+
+/// A subtree reached once through [`TNode::pin_subtree`], so that lookups under its prefix
+/// do not walk the prefix again. Keys outside the prefix are out of reach of the handle.
+#[derive(Debug)]
+pub struct SubtrieHandle<'b, T, M = ()> {
+    prefix: String,
+    node: &'b TNode<T, M>,
+}
+
+impl<'b, T, M: Default> SubtrieHandle<'b, T, M> {
+    /// Returns the content of the full key `key`, like `trie.find(key, true)` followed by
+    /// reading its content.
+    pub fn get(&self, key: &str) -> Option<&'b T> {
+        let rest = key.strip_prefix(self.prefix.as_str())?;
+        self.node.find(rest, true)?.content().as_ref()
+    }
+
+    /// Returns the sorted full keys starting with `prefix`, which must extend the pinned
+    /// prefix.
+    pub fn complete(&self, prefix: &str) -> Vec<String> {
+        let Some(rest) = prefix.strip_prefix(self.prefix.as_str()) else {
+            return vec![];
+        };
+        match self.node.find(rest, false) {
+            Some(n) => n
+                .terminals()
+                .into_iter()
+                .map(|(k, _)| format!("{}{}", prefix, k))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// 64-bit FNV-1a. Wider integers are fed as little-endian bytes, `usize` as a `u64`, so the
+/// result does not depend on the platform.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv1a {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ *b as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, n: u16) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, n: u128) {
+        self.write(&n.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+}
+
+/// Packs bits into bytes, most significant bit first, padding the last byte with zeros.
+fn pack_bits(bits: &[bool]) -> Vec<u8> {
+    bits.chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (i, b)| byte | ((*b as u8) << (7 - i)))
+        })
+        .collect()
+}
+
+impl<T> TNode<T> {
+    /// Returns an empty trie without node metadata.
+    pub fn new() -> Self {
+        TNode::Empty
+    }
+
+    /// Returns an empty [`StrictTrie`], whose `add` rejects the empty key.
+    pub fn strict() -> StrictTrie<T> {
+        StrictTrie { trie: TNode::Empty }
+    }
+}
+
+impl<T, M: Default> TNode<T, M> {
+    fn make_leaf(&mut self) {
         *self = match self {
             TNode::Empty => TNode::Leaf(Leaf {
-                content: &None,
+                content: None,
                 is_terminal: false,
+                version: 0,
+                max_version: 0,
+                metadata: M::default(),
             }),
             TNode::Node(node) => TNode::Leaf(Leaf {
-                content: node.content,
+                content: node.content.take(),
                 is_terminal: node.is_terminal,
+                version: node.version,
+                max_version: node.max_version,
+                metadata: mem::take(&mut node.metadata),
             }),
             _ => panic!("Could not convert to Leaf"),
         }
     }
-    fn to_empty(&mut self) {
-        *self = TNode::Empty;
-    }
-    fn to_node(&mut self) {
+    fn make_node(&mut self) {
         *self = match self {
             TNode::Leaf(leaf) => TNode::Node(Node {
-                content: leaf.content,
+                content: leaf.content.take(),
                 children: BTreeMap::from([]),
                 is_terminal: leaf.is_terminal,
+                version: leaf.version,
+                max_version: leaf.max_version,
+                metadata: mem::take(&mut leaf.metadata),
+                suffix_index: None,
+                terminals: leaf.is_terminal as usize,
             }),
             TNode::Empty => TNode::Node(Node {
-                content: &None,
+                content: None,
                 children: BTreeMap::from([]),
                 is_terminal: false,
+                version: 0,
+                max_version: 0,
+                metadata: M::default(),
+                suffix_index: None,
+                terminals: 0,
             }),
             _ => panic!("Could not convert to Node"),
         }
@@ -127,6 +369,7 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
         }
     }
 
+    #[cfg(test)]
     fn is_empty(&self) -> bool {
         match self {
             TNode::Empty => true,
@@ -137,13 +380,164 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
 
     fn content(&self) -> &Option<T> {
         match self {
-            TNode::Leaf(leaf) => leaf.content,
-            TNode::Node(node) => node.content,
+            TNode::Leaf(leaf) => &leaf.content,
+            TNode::Node(node) => &node.content,
             TNode::Empty => panic!("Cannot call .content() for Empty"),
         }
     }
 
-    pub fn add(&mut self, s: &str, cont: &'a Option<T>) -> Result<&TNode<T>, KeyExists> {
+    fn collect_terminals<'b>(&'b self, acc: &mut String, out: &mut Vec<(String, &'b Self)>) {
+        if self.is_terminal() {
+            out.push((acc.clone(), self));
+        }
+        if let TNode::Node(node) = self {
+            for (k, v) in node.children.iter() {
+                acc.push(*k);
+                v.collect_terminals(acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    fn terminals(&self) -> Vec<(String, &Self)> {
+        let mut out = vec![];
+        self.collect_terminals(&mut String::new(), &mut out);
+        out
+    }
+
+    fn entries(&self) -> Vec<(String, &Option<T>)> {
+        self.terminals()
+            .into_iter()
+            .map(|(k, n)| (k, n.content()))
+            .collect()
+    }
+
+    fn has_terminal_descendant(&self) -> bool {
+        match self {
+            TNode::Node(node) => node
+                .children
+                .values()
+                .any(|c| c.is_terminal() || c.has_terminal_descendant()),
+            _ => false,
+        }
+    }
+
+    fn path_nodes<'b>(&'b self, key: &str) -> impl Iterator<Item = &'b TNode<T, M>> + 'b {
+        let root: &'b TNode<T, M> = self;
+        let chars: Vec<char> = key.chars().collect();
+        let mut pos = 0;
+        std::iter::successors(Some(root), move |cur| match cur {
+            TNode::Node(node) if pos < chars.len() => {
+                pos += 1;
+                node.children.get(&chars[pos - 1])
+            }
+            _ => None,
+        })
+    }
+
+    fn node_mut(&mut self, key: &str) -> Option<&mut Self> {
+        match key.chars().next() {
+            None => Some(self),
+            Some(c) => match self {
+                TNode::Node(node) => node.children.get_mut(&c)?.node_mut(&key[c.len_utf8()..]),
+                _ => None,
+            },
+        }
+    }
+
+    fn count_terminals(&self) -> usize {
+        match self {
+            TNode::Empty => 0,
+            TNode::Leaf(leaf) => leaf.is_terminal as usize,
+            TNode::Node(node) => node.terminals,
+        }
+    }
+
+    fn refresh_terminals(&mut self) {
+        if let TNode::Node(node) = self {
+            node.terminals = node.is_terminal as usize
+                + node
+                    .children
+                    .values()
+                    .map(|c| c.count_terminals())
+                    .sum::<usize>();
+        }
+    }
+
+    fn count_nodes(&self) -> usize {
+        match self {
+            TNode::Empty => 0,
+            TNode::Leaf(_) => 1,
+            TNode::Node(node) => {
+                1 + node
+                    .children
+                    .values()
+                    .map(|c| c.count_nodes())
+                    .sum::<usize>()
+            }
+        }
+    }
+
+    fn into_entries_fn(self, acc: &mut String, out: &mut Vec<(String, Option<T>)>) {
+        match self {
+            TNode::Empty => {}
+            TNode::Leaf(leaf) => {
+                if leaf.is_terminal {
+                    out.push((acc.clone(), leaf.content));
+                }
+            }
+            TNode::Node(node) => {
+                if node.is_terminal {
+                    out.push((acc.clone(), node.content));
+                }
+                for (c, child) in node.children {
+                    acc.push(c);
+                    child.into_entries_fn(acc, out);
+                    acc.pop();
+                }
+            }
+        }
+    }
+
+    fn collect_keys(&self, acc: &mut String, out: &mut Vec<String>) {
+        let mut found = vec![];
+        self.collect_terminals(acc, &mut found);
+        out.extend(found.into_iter().map(|(k, _)| k));
+    }
+
+    fn clear_terminal(&mut self) -> bool {
+        match self {
+            TNode::Empty => false,
+            TNode::Leaf(leaf) => {
+                let was_terminal = leaf.is_terminal;
+                leaf.is_terminal = false;
+                leaf.content = None;
+                was_terminal
+            }
+            TNode::Node(node) => {
+                let was_terminal = node.is_terminal;
+                node.is_terminal = false;
+                node.content = None;
+                node.terminals -= was_terminal as usize;
+                was_terminal
+            }
+        }
+    }
+
+    pub fn add(&mut self, s: &str, cont: Option<T>) -> Result<&TNode<T, M>, KeyExists> {
+        if self.contains_key(s) {
+            return Err(KeyExists);
+        }
+        let version = self.snapshot_version() + 1;
+        self.add_fn(s, cont, version)
+    }
+
+    fn add_fn(
+        &mut self,
+        s: &str,
+        cont: Option<T>,
+        version: u64,
+    ) -> Result<&TNode<T, M>, KeyExists> {
         if s.is_empty() {
             if self.is_terminal() {
                 return Err(KeyExists);
@@ -152,19 +546,25 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
                     TNode::Node(node) => {
                         node.content = cont;
                         node.is_terminal = true;
+                        node.terminals += 1;
+                        node.version = version;
+                        node.max_version = version;
                         return Ok(self);
                     }
-                    TNode::Leaf(_) => {
-                        *self = TNode::Leaf(Leaf {
-                            content: cont,
-                            is_terminal: true,
-                        });
+                    TNode::Leaf(leaf) => {
+                        leaf.content = cont;
+                        leaf.is_terminal = true;
+                        leaf.version = version;
+                        leaf.max_version = version;
                         return Ok(self);
                     }
                     TNode::Empty => {
                         *self = TNode::Leaf(Leaf {
                             content: cont,
                             is_terminal: true,
+                            version,
+                            max_version: version,
+                            metadata: M::default(),
                         });
                         return Ok(self);
                     }
@@ -176,20 +576,16 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
 
         match self {
             TNode::Empty | TNode::Leaf { .. } => {
-                self.to_node();
-                self.add(s, cont)
+                self.make_node();
+                self.add_fn(s, cont, version)
             }
             TNode::Node(node) => {
-                if node.children.contains_key(&first_char) {
-                    node.children.get_mut(&first_char).unwrap().add(rest, cont)
-                } else {
-                    let new_node = TNode::Empty;
-
-                    node.children
-                        .entry(first_char)
-                        .or_insert(new_node)
-                        .add(rest, cont)
-                }
+                node.max_version = version;
+                node.terminals += 1;
+                node.children
+                    .entry(first_char)
+                    .or_insert(TNode::Empty)
+                    .add_fn(rest, cont, version)
             }
         }
     }
@@ -198,166 +594,176 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
         self.find(s, true).is_some()
     }
 
-    pub fn find(&self, s: &str, must_be_terminal: bool) -> Option<&TNode<T>> {
-        let lpo = LongestPrefOpts {
+    pub fn find(&self, s: &str, must_be_terminal: bool) -> Option<&TNode<T, M>> {
+        let lpo = PrefOptions {
             must_be_terminal,
             must_match_fully: true,
+            prefer_terminal: false,
         };
         let last_term = FindResults {
             node: None,
-            prefix: "".to_owned(),
+            prefix_len: 0,
         };
-        self.longest_prefix_fn(s, "", last_term, lpo).node
+        let node = self.longest_prefix_fn(s, 0, last_term, lpo).node;
+        debug_assert_eq!(
+            self.check_find_agrees(s, must_be_terminal, node.is_some()),
+            Ok(())
+        );
+        node
     }
 
-    pub fn longest_prefix(&'a mut self, s: &'a str, must_be_terminal: bool) -> String {
-        let lpo = LongestPrefOpts {
+    pub fn longest_prefix(&self, s: &str, must_be_terminal: bool) -> String {
+        let lpo = PrefOptions {
             must_be_terminal,
             must_match_fully: false,
+            prefer_terminal: false,
         };
+        self.longest_prefix_opts(s, lpo)
+    }
+
+    /// Generalizes [`TNode::longest_prefix`] with every [`PrefOptions`] flag.
+    pub fn longest_prefix_opts(&self, s: &str, opts: PrefOptions) -> String {
         let last_term = FindResults {
             node: None,
-            prefix: "".to_owned(),
+            prefix_len: 0,
         };
-        self.longest_prefix_fn(s, "", last_term, lpo).prefix
+        let res = self.longest_prefix_fn(s, 0, last_term, opts);
+        s[..res.prefix_len].to_owned()
     }
 
-    fn longest_prefix_fn(
-        &self,
-        str_left: &str,
-        str_acc: &str,
-        last_terminal: FindResults<'a, T>,
-        opts: LongestPrefOpts,
-    ) -> FindResults<T> {
+    // `consumed` is the byte length of the part of `query` matched so far, so the matched
+    // prefix is only copied out of `query` once the search is over.
+    fn longest_prefix_fn<'b>(
+        &'b self,
+        query: &str,
+        consumed: usize,
+        last_terminal: FindResults<'b, T, M>,
+        opts: PrefOptions,
+    ) -> FindResults<'b, T, M> {
+        let str_left = &query[consumed..];
+        let no_match = FindResults {
+            node: None,
+            prefix_len: 0,
+        };
+        let current = FindResults {
+            node: Some(self),
+            prefix_len: consumed,
+        };
         match self {
-            TNode::Empty => FindResults {
-                node: None,
-                prefix: "".to_owned(),
-            },
-            TNode::Leaf(leaf) => {
-                let new_last_terminal = if leaf.is_terminal {
-                    FindResults {
-                        node: Some(self),
-                        prefix: str_acc.to_owned(),
-                    }
-                } else {
-                    last_terminal
-                };
-                if str_left.is_empty() {
-                    return if opts.must_be_terminal {
-                        new_last_terminal
-                    } else {
-                        FindResults {
-                            node: Some(self),
-                            prefix: str_acc.to_owned(),
-                        }
-                    };
-                } else {
-                    FindResults {
-                        node: None,
-                        prefix: "".to_owned(),
-                    }
-                }
-            }
+            TNode::Empty => no_match,
+            TNode::Leaf(_) => self.pick_prefix(str_left.is_empty(), current, last_terminal, opts),
             TNode::Node(node) => {
                 let new_last_terminal = if node.is_terminal {
                     FindResults {
                         node: Some(self),
-                        prefix: str_acc.to_owned(),
+                        prefix_len: consumed,
                     }
                 } else {
                     last_terminal
                 };
-                if str_left.is_empty() {
-                    return if opts.must_be_terminal {
-                        new_last_terminal
-                    } else {
-                        FindResults {
-                            node: Some(self),
-                            prefix: str_acc.to_owned(),
-                        }
-                    };
-                };
 
-                let first_char = str_left.chars().next().unwrap();
-                let rest = &str_left[first_char.len_utf8()..];
-                if !node.children.contains_key(&first_char) {
-                    if opts.must_match_fully {
-                        return FindResults {
-                            node: None,
-                            prefix: "".to_owned(),
-                        };
-                    } else {
-                        return FindResults {
-                            node: Some(self),
-                            prefix: str_acc.to_owned(),
-                        };
+                let first_char = match str_left.chars().next() {
+                    Some(c) if node.children.contains_key(&c) => c,
+                    _ => {
+                        return self.pick_prefix(
+                            str_left.is_empty(),
+                            current,
+                            new_last_terminal,
+                            opts,
+                        )
                     }
-                }
+                };
                 let next_node = node.children.get(&first_char).unwrap();
-                let mut new_str_acc = str_acc.to_owned();
-                new_str_acc.push(first_char);
-                return next_node.longest_prefix_fn(
-                    rest,
-                    new_str_acc.as_str(),
+                next_node.longest_prefix_fn(
+                    query,
+                    consumed + first_char.len_utf8(),
                     new_last_terminal,
                     opts,
-                );
+                )
             }
         }
     }
 
-    pub fn pp(&self, print_content: bool) -> String {
-        return self.pp_fn(0, print_content);
+    fn pick_prefix<'b>(
+        &self,
+        full_match: bool,
+        current: FindResults<'b, T, M>,
+        last_terminal: FindResults<'b, T, M>,
+        opts: PrefOptions,
+    ) -> FindResults<'b, T, M> {
+        let must_be_current = opts.must_match_fully && opts.must_be_terminal;
+        if !full_match && opts.must_match_fully || must_be_current && !self.is_terminal() {
+            return FindResults {
+                node: None,
+                prefix_len: 0,
+            };
+        }
+        if self.is_terminal()
+            || !(opts.must_be_terminal || opts.prefer_terminal && last_terminal.node.is_some())
+        {
+            current
+        } else {
+            last_terminal
+        }
     }
 
-    fn pp_fn(&self, indent: u8, print_content: bool) -> String {
-        let mut res = String::from("");
-        match &self {
-            TNode::Empty => {
-                res.push_str("[empty]\n");
-                res
-            }
-            TNode::Leaf { .. } => {
-                if print_content {
-                    res.push_str(format!("  {}", self).as_str());
-                }
-                res.push('\n');
-                res
-            }
-            TNode::Node(node) => {
-                let iter = node.children.iter();
-
-                let child_count = node.children.len();
-
-                for (k, v) in iter {
-                    if node.is_terminal || child_count > 1 {
-                        if indent != 0 {
-                            res.push('\n');
-                        }
-                        res.push_str(&" ".repeat(indent.into()));
-                    }
+    /// Returns how many nodes `remove(prefix, true)` would free: the subtree at `prefix`,
+    /// plus the non-terminal nodes above it that lead nowhere else. The root is never freed.
+    pub fn nodes_under_prefix(&self, prefix: &str) -> usize {
+        let path: Vec<&Self> = self.path_nodes(prefix).collect();
+        if path.len() != prefix.chars().count() + 1 {
+            return 0;
+        }
+        let target = path[path.len() - 1];
+        if prefix.is_empty() {
+            return target.count_nodes().saturating_sub(1);
+        }
+        let dangling = path[1..path.len() - 1]
+            .iter()
+            .rev()
+            .take_while(|n| match n {
+                TNode::Node(node) => !node.is_terminal && node.children.len() == 1,
+                _ => false,
+            })
+            .count();
+        target.count_nodes() + dangling
+    }
 
-                    res.push_str(&k.to_string());
-                    res.push_str(v.pp_fn(indent + 1, print_content).as_str());
-                }
-                res
+    /// Removes `key` and returns whether anything was removed. With `remove_subtree`, every
+    /// key starting with `key` goes too, even when `key` itself is not stored. Otherwise
+    /// only a stored key is removed, and longer keys going through it are kept.
+    pub fn remove(&mut self, key: &str, remove_subtree: bool) -> bool {
+        let removed = self.remove_fn(key, remove_subtree).1;
+        if removed {
+            match self {
+                TNode::Leaf(leaf) => leaf.max_version += 1,
+                TNode::Node(node) => node.max_version += 1,
+                TNode::Empty => {}
             }
         }
+        removed
     }
 
-    fn remove(&mut self, str_left: &'a str, remove_subtree: bool) -> bool {
-        self.remove_fn(str_left, remove_subtree).1
+    fn remove_fn(&mut self, str_left: &str, remove_subtree: bool) -> (bool, bool) {
+        let res = self.remove_step(str_left, remove_subtree);
+        self.refresh_terminals();
+        res
     }
 
-    fn remove_fn(&mut self, str_left: &'a str, remove_subtree: bool) -> (bool, bool) {
+    fn remove_step(&mut self, str_left: &str, remove_subtree: bool) -> (bool, bool) {
+        if str_left.is_empty() {
+            let mut removed = false;
+            if let (true, TNode::Node(node)) = (remove_subtree, &mut *self) {
+                removed = !node.children.is_empty();
+                node.children.clear();
+            }
+            return (false, self.clear_terminal() || removed);
+        }
         let first_char = str_left.chars().next().unwrap();
         let rest = &str_left[first_char.len_utf8()..];
 
         match self {
-            TNode::Empty | TNode::Leaf(_) => {
-                return (false, false);
-            }
+            TNode::Empty | TNode::Leaf(_) => (false, false),
             TNode::Node(node) => {
                 if !node.children.contains_key(&first_char) {
                     return (false, false);
@@ -365,10 +771,12 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
 
                 if rest.is_empty() {
                     match node.children.get_mut(&first_char).unwrap() {
+                        TNode::Leaf(leaf) if !leaf.is_terminal && !remove_subtree => (false, false),
                         TNode::Leaf(_) => {
                             let removed = node.children.remove(&first_char).is_some();
-                            let bubble_up = removed && !node.is_terminal;
-                            return (bubble_up, removed);
+                            let bubble_up =
+                                removed && !node.is_terminal && node.children.is_empty();
+                            (bubble_up, removed)
                         }
                         TNode::Empty => {
                             panic!("Something wrong")
@@ -376,14 +784,17 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
                         TNode::Node(sub_node) => {
                             if remove_subtree {
                                 let removed = node.children.remove(&first_char).is_some();
-                                let bubble_up = removed && !node.is_terminal;
+                                let bubble_up =
+                                    removed && !node.is_terminal && node.children.is_empty();
                                 return (bubble_up, removed);
                             }
                             if !sub_node.is_terminal {
                                 return (false, false);
                             }
                             sub_node.is_terminal = false;
-                            return (true, true);
+                            sub_node.content = None;
+                            sub_node.terminals -= 1;
+                            (false, true)
                         }
                     }
                 } else {
@@ -394,47 +805,2262 @@ impl<'a, T: Display + Debug> TNode<'a, T> {
                         .remove_fn(rest, remove_subtree);
                     let child = node.children.get_mut(&first_char).unwrap();
                     if removed && child.is_childless() {
-                        child.to_leaf();
+                        child.make_leaf();
                     }
                     if bubble_up {
                         let removed = node.children.remove(&first_char).is_some();
-                        let bubble_up = removed && !node.is_terminal;
+                        let bubble_up = removed && !node.is_terminal && node.children.is_empty();
                         return (bubble_up, removed);
                     }
-                    return (false, removed);
+                    (false, removed)
                 }
             }
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::BTreeMap;
+    /// Splits the sorted key space into `n` contiguous shards with roughly the same number of
+    /// keys each. Shard `i` ends at key index `(i + 1) * len / n`, so sizes differ by at most one.
+    /// Each shard is copied straight from its range of the trie, contents cloned.
+    pub fn shard(&self, n: usize) -> Vec<TNode<T, M>>
+    where
+        T: Clone,
+    {
+        let total = self.count_terminals();
+        (0..n)
+            .map(|i| self.copy_rank_range(i * total / n, (i + 1) * total / n))
+            .collect()
+    }
 
-    use super::*;
+    // Copies the keys whose sorted positions lie in `lo..hi`. A running sum of the children's
+    // key counts tells which children overlap the range, so the others are never visited.
+    fn copy_rank_range(&self, lo: usize, hi: usize) -> Self
+    where
+        T: Clone,
+    {
+        if lo >= hi {
+            return TNode::Empty;
+        }
+        match self {
+            TNode::Empty => TNode::Empty,
+            TNode::Leaf(leaf) => TNode::Leaf(Leaf {
+                content: leaf.content.clone(),
+                is_terminal: leaf.is_terminal,
+                version: leaf.version,
+                max_version: leaf.max_version,
+                metadata: M::default(),
+            }),
+            TNode::Node(node) => {
+                let keep_own = node.is_terminal && lo == 0;
+                let mut children = BTreeMap::new();
+                let mut start = node.is_terminal as usize;
+                for (c, child) in node.children.iter() {
+                    if start >= hi {
+                        break;
+                    }
+                    let end = start + child.count_terminals();
+                    if end > lo.max(start) {
+                        let range = lo.saturating_sub(start)..hi.min(end) - start;
+                        children.insert(*c, child.copy_rank_range(range.start, range.end));
+                    }
+                    start = end;
+                }
+                let mut copy = TNode::Node(Node {
+                    content: if keep_own { node.content.clone() } else { None },
+                    children,
+                    is_terminal: keep_own,
+                    version: node.version,
+                    max_version: node.max_version,
+                    metadata: M::default(),
+                    suffix_index: None,
+                    terminals: 0,
+                });
+                copy.refresh_terminals();
+                if copy.is_childless() {
+                    copy.make_leaf();
+                }
+                copy
+            }
+        }
+    }
 
-    #[test]
-    fn pretty_print() {
-        let t: TNode<u8> = TNode::Node(Node {
-            is_terminal: false,
-            content: &None,
-            children: BTreeMap::from([
-                (
-                    'a',
-                    TNode::Node(Node {
-                        is_terminal: true,
-                        content: &None,
-                        children: BTreeMap::from([(
-                            'b',
-                            TNode::Node(Node {
-                                is_terminal: false,
-                                content: &None,
-                                children: BTreeMap::from([(
-                                    'c',
-                                    TNode::Leaf(Leaf {
+    /// Returns the full key when exactly one key starts with `prefix`, `None` when the
+    /// prefix is ambiguous or matches nothing.
+    pub fn unique_completion(&self, prefix: &str) -> Option<String> {
+        let node = self.find(prefix, false)?;
+        if node.count_terminals() != 1 {
+            return None;
+        }
+        node.select(0).map(|suffix| format!("{}{}", prefix, suffix))
+    }
+
+    /// Lazily yields the content of every terminal node met while descending `key`, from the
+    /// shortest prefix to the longest one. Terminals without content are skipped.
+    pub fn content_path<'b>(&'b self, key: &str) -> impl Iterator<Item = &'b T> + 'b {
+        self.path_nodes(key)
+            .filter(|n| n.is_terminal())
+            .filter_map(|n| n.content().as_ref())
+    }
+
+    /// Compares the average key depth with the depth a perfectly balanced trie would need.
+    ///
+    /// With `n` keys, an average depth `d` and an average branching factor `b` (children per
+    /// node that has children), the factor is `d / max(1, log_b(n))`; `log_b(n)` is taken as 1
+    /// when `b <= 1`. Values near 1 mean a bushy trie, large values a stringy one. An empty
+    /// trie has a factor of 0.
+    pub fn balance_factor(&self) -> f64 {
+        let entries = self.entries();
+        if entries.is_empty() {
+            return 0.0;
+        }
+        let n = entries.len() as f64;
+        let depth_sum: usize = entries.iter().map(|(k, _)| k.chars().count()).sum();
+        let avg_depth = depth_sum as f64 / n;
+
+        let (parents, children) = self.branching_stats();
+        let b = if parents == 0 {
+            1.0
+        } else {
+            children as f64 / parents as f64
+        };
+        let min_depth = if b > 1.0 { n.ln() / b.ln() } else { 1.0 };
+        avg_depth / min_depth.max(1.0)
+    }
+
+    fn branching_stats(&self) -> (usize, usize) {
+        match self {
+            TNode::Node(node) if !node.children.is_empty() => {
+                node.children
+                    .values()
+                    .fold((1, node.children.len()), |(p, c), child| {
+                        let (cp, cc) = child.branching_stats();
+                        (p + cp, c + cc)
+                    })
+            }
+            _ => (0, 0),
+        }
+    }
+
+    /// Removes every key shorter than `min_len` characters and returns them in sorted order.
+    pub fn remove_shorter_than(&mut self, min_len: usize) -> Vec<String> {
+        let short: Vec<String> = self
+            .entries()
+            .into_iter()
+            .map(|(k, _)| k)
+            .filter(|k| k.chars().count() < min_len)
+            .collect();
+        for k in &short {
+            self.remove(k, false);
+        }
+        short
+    }
+
+    /// Returns the keys that no other key extends, e.g. only `"abc"` for `"ab"` and `"abc"`.
+    pub fn leaf_keys(&self) -> Vec<String> {
+        self.terminals()
+            .into_iter()
+            .filter(|(_, n)| !n.has_terminal_descendant())
+            .map(|(k, _)| k)
+            .collect()
+    }
+
+    /// Returns the keys that are a proper prefix of at least one other key, e.g. `"ab"` for
+    /// `"ab"` and `"abc"`.
+    pub fn prefix_keys(&self) -> Vec<String> {
+        self.terminals()
+            .into_iter()
+            .filter(|(_, n)| n.has_terminal_descendant())
+            .map(|(k, _)| k)
+            .collect()
+    }
+
+    /// Collapses runs of keys into range notation for display, e.g. `a1`..`a5` into `a[1-5]`.
+    ///
+    /// A run is a sequence of consecutive keys (in sorted order) that only differ in their last
+    /// char, with each last char following the previous one (`'1'`, `'2'`, ...). Runs of a single
+    /// key are left as they are.
+    pub fn summarize_ranges(&self) -> Vec<String> {
+        let mut res = vec![];
+        let mut run: Option<(String, char, char)> = None;
+        let flush = |run: Option<(String, char, char)>, res: &mut Vec<String>| {
+            if let Some((stem, first, last)) = run {
+                if first == last {
+                    res.push(format!("{}{}", stem, first));
+                } else {
+                    res.push(format!("{}[{}-{}]", stem, first, last));
+                }
+            }
+        };
+        for (key, _) in self.entries() {
+            let last_char = match key.chars().last() {
+                Some(c) => c,
+                None => {
+                    res.push(key);
+                    continue;
+                }
+            };
+            let stem = &key[..key.len() - last_char.len_utf8()];
+            match &mut run {
+                Some((s, _, last))
+                    if s == stem && char::from_u32(*last as u32 + 1) == Some(last_char) =>
+                {
+                    *last = last_char;
+                }
+                _ => {
+                    flush(run.take(), &mut res);
+                    run = Some((stem.to_owned(), last_char, last_char));
+                }
+            }
+        }
+        flush(run, &mut res);
+        res
+    }
+
+    /// Returns the content of the shortest key that is a prefix of `s`, i.e. the least specific
+    /// match, or `None` if no key is a prefix of `s` or that key has no content.
+    pub fn shortest_prefix_value(&self, s: &str) -> Option<&T> {
+        self.path_nodes(s)
+            .find(|n| n.is_terminal())
+            .and_then(|n| n.content().as_ref())
+    }
+
+    /// Returns `(matched, total)` where `matched` counts the queries that are keys.
+    pub fn match_rate<'b, I: IntoIterator<Item = &'b str>>(&self, queries: I) -> (usize, usize) {
+        queries.into_iter().fold((0, 0), |(matched, total), q| {
+            (matched + self.contains_key(q) as usize, total + 1)
+        })
+    }
+
+    /// Returns every key paired with whether it is a leaf key, i.e. no other key extends it.
+    pub fn entries_with_leaf_flag(&self) -> Vec<(String, bool)> {
+        self.terminals()
+            .into_iter()
+            .map(|(k, n)| (k, !n.has_terminal_descendant()))
+            .collect()
+    }
+
+    /// Consumes the trie and returns one with the same keys but no payloads, every key holding
+    /// `Some(())`.
+    pub fn drop_contents(self) -> TNode<()> {
+        let unit = |is_terminal: bool| is_terminal.then_some(());
+        match self {
+            TNode::Empty => TNode::Empty,
+            TNode::Leaf(leaf) => TNode::Leaf(Leaf {
+                content: unit(leaf.is_terminal),
+                is_terminal: leaf.is_terminal,
+                version: leaf.version,
+                max_version: leaf.max_version,
+                metadata: (),
+            }),
+            TNode::Node(node) => TNode::Node(Node {
+                content: unit(node.is_terminal),
+                children: node
+                    .children
+                    .into_iter()
+                    .map(|(k, v)| (k, v.drop_contents()))
+                    .collect(),
+                is_terminal: node.is_terminal,
+                version: node.version,
+                max_version: node.max_version,
+                metadata: (),
+                suffix_index: None,
+                terminals: node.terminals,
+            }),
+        }
+    }
+
+    /// Adds `s` like [`TNode::add`] and returns how many of its chars reused existing nodes,
+    /// i.e. the length of the longest prefix of `s` that was already a path in the trie.
+    pub fn add_reporting_shared(&mut self, s: &str, cont: Option<T>) -> Result<usize, KeyExists> {
+        let shared = self.path_nodes(s).count() - 1;
+        self.add(s, cont)?;
+        Ok(shared)
+    }
+
+    /// Returns every `(key, content)` pair whose content satisfies `pred`, in key order.
+    pub fn find_by_value<F: FnMut(&T) -> bool>(&self, mut pred: F) -> Vec<(String, &T)> {
+        self.entries()
+            .into_iter()
+            .filter_map(|(k, c)| c.as_ref().filter(|v| pred(v)).map(|v| (k, v)))
+            .collect()
+    }
+
+    /// Returns the keys whose every non-empty prefix is also a key, e.g. `"abc"` when `"a"`
+    /// and `"ab"` are keys too.
+    pub fn terminal_chain_keys(&self) -> Vec<String> {
+        let mut res = vec![];
+        if self.is_terminal() {
+            res.push(String::new());
+        }
+        self.terminal_chain_fn(&mut String::new(), &mut res);
+        res
+    }
+
+    fn terminal_chain_fn(&self, acc: &mut String, out: &mut Vec<String>) {
+        if let TNode::Node(node) = self {
+            for (k, v) in node.children.iter().filter(|(_, v)| v.is_terminal()) {
+                acc.push(*k);
+                out.push(acc.clone());
+                v.terminal_chain_fn(acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Like [`TNode::longest_prefix`], but also returns whether the matched prefix is a key
+    /// and whether it covers all of `s`, as `(prefix, is_terminal, full_match)`.
+    pub fn longest_prefix_detailed(
+        &self,
+        s: &str,
+        must_be_terminal: bool,
+    ) -> Option<(String, bool, bool)> {
+        let lpo = PrefOptions {
+            must_be_terminal,
+            must_match_fully: false,
+            prefer_terminal: false,
+        };
+        let last_term = FindResults {
+            node: None,
+            prefix_len: 0,
+        };
+        let res = self.longest_prefix_fn(s, 0, last_term, lpo);
+        Some((
+            s[..res.prefix_len].to_owned(),
+            res.node?.is_terminal(),
+            res.prefix_len == s.len(),
+        ))
+    }
+
+    /// Maps every distinct content to the sorted keys holding it. Keys without content are
+    /// left out.
+    pub fn value_index(&self) -> BTreeMap<T, Vec<String>>
+    where
+        T: Ord + Clone,
+    {
+        let mut index: BTreeMap<T, Vec<String>> = BTreeMap::new();
+        for (k, c) in self.entries() {
+            if let Some(v) = c {
+                index.entry(v.clone()).or_default().push(k);
+            }
+        }
+        index
+    }
+
+    /// Hashes every `(key, content)` pair in key order, so tries holding the same entries hash
+    /// the same however they were built. Uses 64-bit FNV-1a with integers fed little-endian,
+    /// so the fingerprint is the same across Rust releases and platforms as long as the
+    /// `Hash` impl of `T` does not change.
+    pub fn content_hash(&self) -> u64
+    where
+        T: Hash,
+    {
+        let mut hasher = Fnv1a::default();
+        for (k, c) in self.entries() {
+            hasher.write(k.as_bytes());
+            hasher.write_u8(0xff);
+            match c {
+                Some(c) => {
+                    hasher.write_u8(1);
+                    c.hash(&mut hasher);
+                }
+                None => hasher.write_u8(0),
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Returns a counter that grows with every insertion or removal. Pass it to
+    /// [`TNode::keys_since`] later on to get the keys added in between.
+    ///
+    /// Every node stores its insertion version and the highest version written below it,
+    /// which costs two `u64`s per node.
+    pub fn snapshot_version(&self) -> u64 {
+        match self {
+            TNode::Empty => 0,
+            TNode::Leaf(leaf) => leaf.max_version,
+            TNode::Node(node) => node.max_version,
+        }
+    }
+
+    /// Returns the keys inserted after `version` was taken with [`TNode::snapshot_version`],
+    /// skipping subtrees that have not been written to since.
+    pub fn keys_since(&self, version: u64) -> Vec<String> {
+        let mut res = vec![];
+        self.keys_since_fn(version, &mut String::new(), &mut res);
+        res
+    }
+
+    fn keys_since_fn(&self, version: u64, acc: &mut String, out: &mut Vec<String>) {
+        let (node_version, children) = match self {
+            TNode::Empty => return,
+            TNode::Leaf(leaf) => (leaf.version, None),
+            TNode::Node(node) => (node.version, Some(&node.children)),
+        };
+        if self.is_terminal() && node_version > version {
+            out.push(acc.clone());
+        }
+        for (k, v) in children.into_iter().flatten() {
+            if v.snapshot_version() > version {
+                acc.push(*k);
+                v.keys_since_fn(version, acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Returns the keys that have no other key as a prefix. Every key starts with exactly one
+    /// of them, so they summarize the key space by its top-level groups.
+    pub fn covering_prefixes(&self) -> Vec<String> {
+        let mut res = vec![];
+        self.covering_prefixes_fn(&mut String::new(), &mut res);
+        res
+    }
+
+    fn covering_prefixes_fn(&self, acc: &mut String, out: &mut Vec<String>) {
+        if self.is_terminal() {
+            out.push(acc.clone());
+            return;
+        }
+        if let TNode::Node(node) = self {
+            for (k, v) in node.children.iter() {
+                acc.push(*k);
+                v.covering_prefixes_fn(acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Adds a key made of whole tokens, e.g. the words of a phrase. Each token is followed by
+    /// [`TOKEN_SEPARATOR`], so tokens never match a part of a longer token. A token
+    /// containing the separator is rejected, as it would collide with two shorter tokens.
+    pub fn add_tokens(
+        &mut self,
+        tokens: &[&str],
+        cont: Option<T>,
+    ) -> Result<&TNode<T, M>, TokenAddError> {
+        let key = Self::token_key(tokens).ok_or(TokenAddError::SeparatorInToken)?;
+        Ok(self.add(&key, cont)?)
+    }
+
+    /// Returns the node reached by a sequence of whole tokens added with
+    /// [`TNode::add_tokens`], whether or not it ends a key. `None` when a token contains
+    /// [`TOKEN_SEPARATOR`].
+    pub fn find_tokens(&self, tokens: &[&str]) -> Option<&TNode<T, M>> {
+        self.find(&Self::token_key(tokens)?, false)
+    }
+
+    fn token_key(tokens: &[&str]) -> Option<String> {
+        if tokens.iter().any(|t| t.contains(TOKEN_SEPARATOR)) {
+            return None;
+        }
+        Some(tokens.iter().fold(String::new(), |mut key, t| {
+            key.push_str(t);
+            key.push(TOKEN_SEPARATOR);
+            key
+        }))
+    }
+
+    /// Returns `(node_count, minimized_node_count)`, where the second value is the number of
+    /// distinct subtrees, i.e. the node count after merging identical subtrees the way
+    /// minimization would. Nothing is rebuilt.
+    pub fn minimization_estimate(&self) -> (usize, usize)
+    where
+        T: Hash + Eq,
+    {
+        let mut distinct = HashMap::new();
+        let mut count = 0;
+        if !matches!(self, TNode::Empty) {
+            self.subtree_id(&mut distinct, &mut count);
+        }
+        (count, distinct.len())
+    }
+
+    fn subtree_id<'b>(
+        &'b self,
+        distinct: &mut HashMap<SubtreeSig<'b, T>, usize>,
+        count: &mut usize,
+    ) -> usize
+    where
+        T: Hash + Eq,
+    {
+        *count += 1;
+        let children = match self {
+            TNode::Node(node) => node
+                .children
+                .iter()
+                .map(|(k, v)| (*k, v.subtree_id(distinct, count)))
+                .collect(),
+            _ => vec![],
+        };
+        let content = if self.is_terminal() {
+            self.content().as_ref()
+        } else {
+            None
+        };
+        let next_id = distinct.len();
+        *distinct
+            .entry((self.is_terminal(), content, children))
+            .or_insert(next_id)
+    }
+
+    /// Returns up to `limit` keys strictly greater than `after` (or from the first key when
+    /// `after` is `None`), in sorted order. Subtrees holding only smaller keys are skipped, so
+    /// pass the last key of a page to get the next one.
+    pub fn keys_page(&self, after: Option<&str>, limit: usize) -> Vec<String> {
+        let mut res = vec![];
+        if limit > 0 {
+            self.keys_page_fn(after, limit, &mut String::new(), &mut res);
+        }
+        res
+    }
+
+    // `bound` is the part of `after` not matched by `acc` yet, `None` once every key below
+    // is known to be greater than `after`.
+    fn keys_page_fn(
+        &self,
+        bound: Option<&str>,
+        limit: usize,
+        acc: &mut String,
+        out: &mut Vec<String>,
+    ) -> bool {
+        if bound.is_none() && self.is_terminal() {
+            out.push(acc.clone());
+            if out.len() == limit {
+                return true;
+            }
+        }
+        if let TNode::Node(node) = self {
+            let first = bound.and_then(|b| b.chars().next());
+            for (k, v) in node.children.iter() {
+                let child_bound = match first {
+                    Some(c) if *k < c => continue,
+                    Some(c) if *k == c => Some(&bound.unwrap()[c.len_utf8()..]),
+                    _ => None,
+                };
+                acc.push(*k);
+                let done = v.keys_page_fn(child_bound, limit, acc, out);
+                acc.pop();
+                if done {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Returns the metadata attached to this node. Like `content`, panics for `Empty`.
+    pub fn metadata(&self) -> &M {
+        match self {
+            TNode::Leaf(leaf) => &leaf.metadata,
+            TNode::Node(node) => &node.metadata,
+            TNode::Empty => panic!("Cannot call .metadata() for Empty"),
+        }
+    }
+
+    /// Sets the metadata of the node reached by `path`, which does not need to be a key.
+    /// Returns `false` when there is no such node.
+    pub fn set_metadata(&mut self, path: &str, metadata: M) -> bool {
+        match self.node_mut(path) {
+            Some(TNode::Leaf(leaf)) => leaf.metadata = metadata,
+            Some(TNode::Node(node)) => node.metadata = metadata,
+            _ => return false,
+        }
+        true
+    }
+
+    /// Returns `true` if no key is a proper prefix of another key, i.e. the keys form a
+    /// prefix code.
+    pub fn is_prefix_free(&self) -> bool {
+        match self {
+            TNode::Node(node) => {
+                !(node.is_terminal && self.has_terminal_descendant())
+                    && node.children.values().all(|c| c.is_prefix_free())
+            }
+            _ => true,
+        }
+    }
+
+    /// Groups the keys by their length in chars, each group sorted.
+    pub fn keys_by_length(&self) -> BTreeMap<usize, Vec<String>> {
+        let mut groups: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (k, _) in self.entries() {
+            groups.entry(k.chars().count()).or_default().push(k);
+        }
+        groups
+    }
+
+    /// Returns the keys matching `pattern`, where the chars starting at the byte offsets in
+    /// `wildcard_positions` match any single char. Other chars must match exactly, so matching
+    /// keys have as many chars as `pattern`.
+    pub fn matches_mask(&self, pattern: &str, wildcard_positions: &[usize]) -> Vec<String> {
+        let mask: Vec<Option<char>> = pattern
+            .char_indices()
+            .map(|(i, c)| (!wildcard_positions.contains(&i)).then_some(c))
+            .collect();
+        let mut res = vec![];
+        self.matches_mask_fn(&mask, &mut String::new(), &mut res);
+        res
+    }
+
+    fn matches_mask_fn(&self, mask: &[Option<char>], acc: &mut String, out: &mut Vec<String>) {
+        let (first, rest) = match mask.split_first() {
+            None => {
+                if self.is_terminal() {
+                    out.push(acc.clone());
+                }
+                return;
+            }
+            Some(split) => split,
+        };
+        if let TNode::Node(node) = self {
+            for (k, v) in node.children.iter() {
+                if first.is_some_and(|c| c != *k) {
+                    continue;
+                }
+                acc.push(*k);
+                v.matches_mask_fn(rest, acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Counts the keys in `[start, end)` without building them, skipping every subtree that
+    /// lies entirely outside the range.
+    pub fn count_range(&self, start: &str, end: &str) -> usize {
+        self.count_range_fn(Some(start), Some(end))
+    }
+
+    // `lo` and `hi` are the parts of the bounds not matched yet; `None` means every key below
+    // is already known to be on the right side of that bound.
+    fn count_range_fn(&self, lo: Option<&str>, hi: Option<&str>) -> usize {
+        if lo.is_none() && hi.is_none() {
+            return self.count_terminals();
+        }
+        let mut count = 0;
+        if self.is_terminal() && lo.is_none_or(str::is_empty) && hi != Some("") {
+            count += 1;
+        }
+        let node = match self {
+            TNode::Node(node) if hi != Some("") => node,
+            _ => return count,
+        };
+        let lo_first = lo.and_then(|l| l.chars().next());
+        let hi_first = hi.and_then(|h| h.chars().next());
+        for (k, v) in node.children.iter() {
+            let child_lo = match lo_first {
+                Some(c) if *k < c => continue,
+                Some(c) if *k == c => Some(&lo.unwrap()[c.len_utf8()..]),
+                _ => None,
+            };
+            let child_hi = match hi_first {
+                Some(c) if *k > c => break,
+                Some(c) if *k == c => Some(&hi.unwrap()[c.len_utf8()..]),
+                _ => None,
+            };
+            count += v.count_range_fn(child_lo, child_hi);
+        }
+        count
+    }
+
+    /// Returns the key sharing the longest prefix with `query`, along with the length of that
+    /// prefix in chars. Ties go to the smallest key. Returns `None` when no key shares even
+    /// one char with `query`.
+    pub fn best_prefix_match(&self, query: &str) -> Option<(String, usize)> {
+        let path: Vec<&TNode<T, M>> = self.path_nodes(query).collect();
+        let (depth, node) = path
+            .iter()
+            .enumerate()
+            .skip(1)
+            .rev()
+            .find(|(_, n)| n.is_terminal() || n.has_terminal_descendant())?;
+        let prefix: String = query.chars().take(depth).collect();
+        let (suffix, _) = node.terminals().into_iter().next()?;
+        Some((prefix + &suffix, depth))
+    }
+
+    /// Writes the keys in front-coded form: one `shared:suffix` line per key, where `shared`
+    /// is the number of leading chars taken from the previous key, e.g. `0:apple` then `4:y`
+    /// for "apply". Contents are not written, and keys must not contain newlines.
+    pub fn to_compact_text(&self) -> String {
+        let mut res = String::new();
+        let mut prev = String::new();
+        for (key, _) in self.entries() {
+            let shared = prev
+                .chars()
+                .zip(key.chars())
+                .take_while(|(a, b)| a == b)
+                .count();
+            let suffix: String = key.chars().skip(shared).collect();
+            res.push_str(&format!("{}:{}\n", shared, suffix));
+            prev = key;
+        }
+        res
+    }
+
+    /// Indexes the reversed keys so that [`TNode::keys_with_suffix`] only walks the matches.
+    ///
+    /// The index is a snapshot: it must be rebuilt after adding or removing keys. A stale
+    /// index is detected through [`TNode::snapshot_version`] and ignored in favour of a scan.
+    pub fn build_suffix_index(&mut self) {
+        let version = self.snapshot_version();
+        let mut reversed = TNode::new();
+        for (key, _) in self.terminals() {
+            let rev: String = key.chars().rev().collect();
+            reversed.add(&rev, Some(())).unwrap();
+        }
+        if let TNode::Node(node) = self {
+            node.suffix_index = Some(Box::new(SuffixIndex { version, reversed }));
+        }
+    }
+
+    /// Returns the keys ending in `suffix`, sorted. Takes O(suffix length + matches) plus
+    /// the final sort with an up-to-date [`TNode::build_suffix_index`], a full scan otherwise.
+    pub fn keys_with_suffix(&self, suffix: &str) -> Vec<String> {
+        let mut keys: Vec<String> = match self {
+            TNode::Node(Node {
+                suffix_index: Some(index),
+                max_version,
+                ..
+            }) if index.version == *max_version => {
+                let rev: String = suffix.chars().rev().collect();
+                match index.reversed.find(&rev, false) {
+                    Some(n) => n
+                        .terminals()
+                        .into_iter()
+                        .map(|(k, _)| k.chars().rev().chain(suffix.chars()).collect())
+                        .collect(),
+                    None => vec![],
+                }
+            }
+            _ => self
+                .terminals()
+                .into_iter()
+                .map(|(k, _)| k)
+                .filter(|k| k.ends_with(suffix))
+                .collect(),
+        };
+        keys.sort();
+        keys
+    }
+
+    /// Returns the average key length in chars, each key weighted by its content (e.g. a
+    /// usage count). Keys without content are skipped; 0 when the weights sum to 0.
+    pub fn weighted_avg_key_len(&self) -> f64
+    where
+        T: Into<f64> + Clone,
+    {
+        let (mut total, mut weights) = (0.0, 0.0);
+        for (key, content) in self.entries() {
+            if let Some(c) = content {
+                let w: f64 = c.clone().into();
+                total += w * key.chars().count() as f64;
+                weights += w;
+            }
+        }
+        if weights == 0.0 {
+            0.0
+        } else {
+            total / weights
+        }
+    }
+
+    /// Adds a batch of keys sorted in ascending order. Each key is reached from the deepest
+    /// node it shares with the previous one instead of from the root, so long shared
+    /// prefixes are walked once. Unsorted input is still merged correctly, only slower.
+    /// Keys already in the trie keep their content.
+    pub fn merge_sorted<I: IntoIterator<Item = (String, Option<T>)>>(&mut self, sorted: I) {
+        let mut version = self.snapshot_version();
+        self.merge_fn(
+            &mut sorted.into_iter().peekable(),
+            &mut String::new(),
+            &mut version,
+        );
+    }
+
+    fn merge_fn<I: Iterator<Item = (String, Option<T>)>>(
+        &mut self,
+        sorted: &mut std::iter::Peekable<I>,
+        prefix: &mut String,
+        version: &mut u64,
+    ) {
+        while let Some((key, _)) = sorted.peek() {
+            if !key.starts_with(prefix.as_str()) {
+                break;
+            }
+            let next = key[prefix.len()..].chars().next();
+            let Some(c) = next else {
+                let (_, cont) = sorted.next().unwrap();
+                if !self.is_terminal() {
+                    *version += 1;
+                    let _ = self.add_fn("", cont, *version);
+                }
+                continue;
+            };
+            if !matches!(self, TNode::Node(_)) {
+                self.make_node();
+            }
+            if let TNode::Node(node) = self {
+                let child = node.children.entry(c).or_insert(TNode::Empty);
+                prefix.push(c);
+                child.merge_fn(sorted, prefix, version);
+                prefix.pop();
+                node.max_version = node.max_version.max(child.snapshot_version());
+            }
+        }
+        self.refresh_terminals();
+    }
+
+    /// Returns the largest number of children of any node, 0 for a trie without edges.
+    pub fn max_fanout(&self) -> usize {
+        match self {
+            TNode::Node(node) => node
+                .children
+                .values()
+                .map(|c| c.max_fanout())
+                .fold(node.children.len(), usize::max),
+            _ => 0,
+        }
+    }
+
+    /// Returns the sorted keys starting with `prefix` whose length in chars lies within
+    /// `[min_len, max_len]`. Branches are not descended past `max_len`.
+    pub fn completions_bounded(&self, prefix: &str, min_len: usize, max_len: usize) -> Vec<String> {
+        let mut out = vec![];
+        if let Some(node) = self.find(prefix, false) {
+            let len = prefix.chars().count();
+            node.completions_bounded_fn(&mut prefix.to_string(), len, (min_len, max_len), &mut out);
+        }
+        out
+    }
+
+    fn completions_bounded_fn(
+        &self,
+        acc: &mut String,
+        len: usize,
+        (min_len, max_len): (usize, usize),
+        out: &mut Vec<String>,
+    ) {
+        if len > max_len {
+            return;
+        }
+        if self.is_terminal() && len >= min_len {
+            out.push(acc.clone());
+        }
+        if let TNode::Node(node) = self {
+            for (c, child) in node.children.iter() {
+                acc.push(*c);
+                child.completions_bounded_fn(acc, len + 1, (min_len, max_len), out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Lazily visits every node in pre-order, internal ones included, yielding its prefix,
+    /// whether it is terminal and how many children it has. An empty trie yields nothing.
+    pub fn walk(&self) -> impl Iterator<Item = (String, bool, usize)> + '_ {
+        let mut stack = match self {
+            TNode::Empty => vec![],
+            _ => vec![(String::new(), self)],
+        };
+        std::iter::from_fn(move || {
+            let (prefix, n) = stack.pop()?;
+            let children = match n {
+                TNode::Node(node) => {
+                    for (c, child) in node.children.iter().rev() {
+                        stack.push((format!("{}{}", prefix, c), child));
+                    }
+                    node.children.len()
+                }
+                _ => 0,
+            };
+            Some((prefix, n.is_terminal(), children))
+        })
+    }
+
+    /// Returns `|intersection| / |union|` of the key sets of both tries, walking them side by
+    /// side. Two empty tries are identical, so they score 1.
+    pub fn jaccard(&self, other: &TNode<T, M>) -> f64 {
+        let (inter, union) = self.jaccard_fn(other);
+        if union == 0 {
+            1.0
+        } else {
+            inter as f64 / union as f64
+        }
+    }
+
+    fn jaccard_fn(&self, other: &TNode<T, M>) -> (usize, usize) {
+        let (a, b) = (self.is_terminal(), other.is_terminal());
+        let (mut inter, mut union) = ((a && b) as usize, (a || b) as usize);
+        match (self, other) {
+            (TNode::Node(x), TNode::Node(y)) => {
+                for (c, child) in x.children.iter() {
+                    match y.children.get(c) {
+                        Some(o) => {
+                            let (i, u) = child.jaccard_fn(o);
+                            inter += i;
+                            union += u;
+                        }
+                        None => union += child.count_terminals(),
+                    }
+                }
+                for (c, o) in y.children.iter() {
+                    if !x.children.contains_key(c) {
+                        union += o.count_terminals();
+                    }
+                }
+            }
+            (TNode::Node(_), _) => union += self.count_terminals() - a as usize,
+            (_, TNode::Node(_)) => union += other.count_terminals() - b as usize,
+            _ => {}
+        }
+        (inter, union)
+    }
+
+    /// Removes every key matching `pattern`, where `*` matches any run of chars and `?` a
+    /// single char, and returns the removed keys sorted.
+    pub fn remove_glob(&mut self, pattern: &str) -> Vec<String> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let mut matched = vec![];
+        self.glob_fn(&pattern, &mut String::new(), &mut matched);
+        matched.sort();
+        matched.dedup();
+        for k in &matched {
+            self.remove(k, false);
+        }
+        matched
+    }
+
+    fn glob_fn(&self, pattern: &[char], acc: &mut String, out: &mut Vec<String>) {
+        let Some((&p, rest)) = pattern.split_first() else {
+            if self.is_terminal() {
+                out.push(acc.clone());
+            }
+            return;
+        };
+        if p == '*' {
+            self.glob_fn(rest, acc, out);
+        }
+        if let TNode::Node(node) = self {
+            for (c, child) in node.children.iter() {
+                let next = match p {
+                    '*' => pattern,
+                    '?' => rest,
+                    _ if p == *c => rest,
+                    _ => continue,
+                };
+                acc.push(*c);
+                child.glob_fn(next, acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Lists every node visited while looking up the longest prefix of `s`, from the root
+    /// down, with its prefix, terminal flag and content.
+    pub fn longest_prefix_trace(&self, s: &str) -> Vec<(String, bool, Option<&T>)> {
+        let ends = std::iter::once(0).chain(s.char_indices().map(|(i, c)| i + c.len_utf8()));
+        self.path_nodes(s)
+            .zip(ends)
+            .filter(|(n, _)| !matches!(n, TNode::Empty))
+            .map(|(n, end)| (s[..end].to_string(), n.is_terminal(), n.content().as_ref()))
+            .collect()
+    }
+
+    /// Returns up to `n` keys spread evenly over the sorted keys, for a preview that is not
+    /// just the first `n`. All keys are returned when there are at most `n`.
+    pub fn sample_keys(&self, n: usize) -> Vec<String> {
+        let keys: Vec<String> = self.terminals().into_iter().map(|(k, _)| k).collect();
+        let total = keys.len();
+        if n >= total {
+            return keys;
+        }
+        (0..n).map(|i| keys[i * total / n].clone()).collect()
+    }
+
+    /// Returns the sorted keys containing at least one non-ASCII char, e.g. to spot encoding
+    /// issues or entries needing normalization.
+    pub fn non_ascii_keys(&self) -> Vec<String> {
+        self.terminals()
+            .into_iter()
+            .map(|(k, _)| k)
+            .filter(|k| !k.is_ascii())
+            .collect()
+    }
+
+    /// Rebuilds the trie with `f` applied to every key. When several keys map to the same
+    /// key, the first one in the original sorted order keeps its content and the others
+    /// are dropped. Versions restart and metadata is reset.
+    pub fn map_keys<F: FnMut(&str) -> String>(self, mut f: F) -> TNode<T, M> {
+        let mut t = TNode::Empty;
+        for (key, cont) in self.into_sorted_vec() {
+            let _ = t.add(&f(&key), cont);
+        }
+        t
+    }
+
+    /// Packs the sorted keys into one UTF-8 buffer, each key followed by a `\0` byte, for FFI
+    /// or mmap consumers. Keys must not contain `\0` themselves.
+    pub fn keys_packed(&self) -> Vec<u8> {
+        let mut out = vec![];
+        for (key, _) in self.terminals() {
+            out.extend_from_slice(key.as_bytes());
+            out.push(0);
+        }
+        out
+    }
+
+    /// Returns the longest prefix shared by all `queries` that is also a path in the trie,
+    /// `None` for an empty trie or no queries.
+    pub fn deepest_common_node(&self, queries: &[&str]) -> Option<String> {
+        let (first, rest) = queries.split_first()?;
+        if matches!(self, TNode::Empty) {
+            return None;
+        }
+        let shared = rest.iter().fold(first.len(), |len, q| {
+            first[..len]
+                .char_indices()
+                .zip(q.chars())
+                .find(|((_, a), b)| a != b)
+                .map_or(len.min(q.len()), |((i, _), _)| i)
+        });
+        let common = &first[..shared];
+        let depth = self.path_nodes(common).count() - 1;
+        Some(common.chars().take(depth).collect())
+    }
+
+    /// Returns whether every key has content satisfying `pred`, stopping at the first
+    /// failure. A key without content fails. An empty trie passes.
+    pub fn all_values<F: FnMut(&T) -> bool>(&self, mut pred: F) -> bool {
+        self.all_values_fn(&mut pred)
+    }
+
+    fn all_values_fn<F: FnMut(&T) -> bool>(&self, pred: &mut F) -> bool {
+        if self.is_terminal() && !self.content().as_ref().is_some_and(&mut *pred) {
+            return false;
+        }
+        match self {
+            TNode::Node(node) => node.children.values().all(|c| c.all_values_fn(pred)),
+            _ => true,
+        }
+    }
+
+    /// Returns the sorted keys concatenated in one buffer, with the byte range of each key
+    /// in it, so consumers slice a single allocation instead of holding a `String` per key.
+    pub fn keys_interned(&self) -> (String, Vec<Range<usize>>) {
+        let mut buf = String::new();
+        let mut ranges = vec![];
+        for (key, _) in self.terminals() {
+            let start = buf.len();
+            buf.push_str(&key);
+            ranges.push(start..buf.len());
+        }
+        (buf, ranges)
+    }
+
+    /// Returns the number of nodes at each depth, the root being at depth 0. An empty trie
+    /// has no levels.
+    pub fn depth_profile(&self) -> Vec<usize> {
+        let mut profile = vec![];
+        let mut level: Vec<&Self> = match self {
+            TNode::Empty => vec![],
+            _ => vec![self],
+        };
+        while !level.is_empty() {
+            profile.push(level.len());
+            level = level
+                .into_iter()
+                .filter_map(|n| match n {
+                    TNode::Node(node) => Some(node.children.values()),
+                    _ => None,
+                })
+                .flatten()
+                .collect();
+        }
+        profile
+    }
+
+    /// Returns whether adding `keys` would create more than `budget` nodes, without
+    /// inserting anything. Prefixes already in the trie or shared within the batch are only
+    /// counted once.
+    pub fn would_exceed_nodes(&self, keys: &[&str], budget: usize) -> bool {
+        let mut new = HashSet::new();
+        for key in keys {
+            let existing = match self {
+                TNode::Empty => 0,
+                _ => self.path_nodes(key).count(),
+            };
+            let ends = key.char_indices().map(|(i, _)| i).chain([key.len()]);
+            new.extend(ends.skip(existing).map(|end| &key[..end]));
+            if new.len() > budget {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns the prefixes of non-terminal nodes with no key below them, e.g. left behind
+    /// by a partial batch. Only the top of each dead branch is listed; the root itself is
+    /// never reported, so a trie emptied by removals yields nothing.
+    pub fn find_dead_branches(&self) -> Vec<String> {
+        let mut out = vec![];
+        self.dead_branches_fn(&mut String::new(), &mut out);
+        out
+    }
+
+    /// Returns whether a key ends at or below this node.
+    fn dead_branches_fn(&self, acc: &mut String, out: &mut Vec<String>) -> bool {
+        let mut live = self.is_terminal();
+        if let TNode::Node(node) = self {
+            for (c, child) in node.children.iter() {
+                acc.push(*c);
+                let mark = out.len();
+                if child.dead_branches_fn(acc, out) {
+                    live = true;
+                } else {
+                    out.truncate(mark);
+                    out.push(acc.clone());
+                }
+                acc.pop();
+            }
+        }
+        live
+    }
+
+    /// Folds `f` over the contents of the keys starting with `prefix`, in key order. Keys
+    /// without content are skipped; `init` is returned when nothing matches.
+    pub fn aggregate_prefix<A, F: FnMut(A, &T) -> A>(&self, prefix: &str, init: A, mut f: F) -> A {
+        match self.find(prefix, false) {
+            Some(node) => node.aggregate_fn(init, &mut f),
+            None => init,
+        }
+    }
+
+    fn aggregate_fn<A, F: FnMut(A, &T) -> A>(&self, mut acc: A, f: &mut F) -> A {
+        if self.is_terminal() {
+            if let Some(c) = self.content() {
+                acc = f(acc, c);
+            }
+        }
+        if let TNode::Node(node) = self {
+            for child in node.children.values() {
+                acc = child.aggregate_fn(acc, f);
+            }
+        }
+        acc
+    }
+
+    /// Returns whether some key uses exactly the chars of `query`, in any order.
+    pub fn has_anagram(&self, query: &str) -> bool {
+        !self.anagrams(query).is_empty()
+    }
+
+    /// Returns the sorted keys using exactly the chars of `query`, in any order; `query`
+    /// itself is included when it is a key. Only branches whose chars are still available
+    /// are descended.
+    pub fn anagrams(&self, query: &str) -> Vec<String> {
+        let mut available: HashMap<char, usize> = HashMap::new();
+        for c in query.chars() {
+            *available.entry(c).or_default() += 1;
+        }
+        let mut out = vec![];
+        self.anagrams_fn(
+            &mut available,
+            query.chars().count(),
+            &mut String::new(),
+            &mut out,
+        );
+        out
+    }
+
+    fn anagrams_fn(
+        &self,
+        available: &mut HashMap<char, usize>,
+        left: usize,
+        acc: &mut String,
+        out: &mut Vec<String>,
+    ) {
+        if left == 0 {
+            if self.is_terminal() {
+                out.push(acc.clone());
+            }
+            return;
+        }
+        if let TNode::Node(node) = self {
+            for (c, child) in node.children.iter() {
+                match available.get_mut(c) {
+                    Some(n) if *n > 0 => *n -= 1,
+                    _ => continue,
+                }
+                acc.push(*c);
+                child.anagrams_fn(available, left - 1, acc, out);
+                acc.pop();
+                *available.get_mut(c).unwrap() += 1;
+            }
+        }
+    }
+
+    /// Returns the number of keys strictly less than `key`, whether or not `key` is stored.
+    /// Takes O(key length) steps, reading the key count each node caches for its subtree.
+    pub fn rank(&self, key: &str) -> usize {
+        self.count_range_fn(None, Some(key))
+    }
+
+    /// Returns the key at position `rank` in sorted order, the inverse of [`TNode::rank`].
+    /// Subtrees before the target are skipped by their cached key count, so this takes
+    /// O(result length) steps.
+    pub fn select(&self, mut rank: usize) -> Option<String> {
+        let mut key = String::new();
+        let mut cur = self;
+        loop {
+            if cur.is_terminal() {
+                if rank == 0 {
+                    return Some(key);
+                }
+                rank -= 1;
+            }
+            let TNode::Node(node) = cur else {
+                return None;
+            };
+            let mut next = None;
+            for (c, child) in node.children.iter() {
+                let n = child.count_terminals();
+                if rank < n {
+                    next = Some((c, child));
+                    break;
+                }
+                rank -= n;
+            }
+            let (c, child) = next?;
+            key.push(*c);
+            cur = child;
+        }
+    }
+
+    /// Returns the key at `index` in sorted order, so that together with
+    /// [`TNode::key_count`] the trie can be read like a sorted array. Same as
+    /// [`TNode::select`].
+    pub fn key_at(&self, index: usize) -> Option<String> {
+        self.select(index)
+    }
+
+    /// Returns the number of keys stored.
+    pub fn key_count(&self) -> usize {
+        self.count_terminals()
+    }
+
+    /// Returns the sorted keys only found in `self` and those only found in `other`,
+    /// walking both tries side by side.
+    pub fn symmetric_difference(&self, other: &TNode<T, M>) -> (Vec<String>, Vec<String>) {
+        let (mut mine, mut theirs) = (vec![], vec![]);
+        self.symmetric_difference_fn(other, &mut String::new(), &mut mine, &mut theirs);
+        mine.sort();
+        theirs.sort();
+        (mine, theirs)
+    }
+
+    fn symmetric_difference_fn(
+        &self,
+        other: &TNode<T, M>,
+        acc: &mut String,
+        mine: &mut Vec<String>,
+        theirs: &mut Vec<String>,
+    ) {
+        match (self.is_terminal(), other.is_terminal()) {
+            (true, false) => mine.push(acc.clone()),
+            (false, true) => theirs.push(acc.clone()),
+            _ => {}
+        }
+        let (no_x, no_y) = (BTreeMap::new(), BTreeMap::new());
+        let xs = match self {
+            TNode::Node(node) => &node.children,
+            _ => &no_x,
+        };
+        let ys = match other {
+            TNode::Node(node) => &node.children,
+            _ => &no_y,
+        };
+        for (c, x) in xs.iter() {
+            acc.push(*c);
+            match ys.get(c) {
+                Some(y) => x.symmetric_difference_fn(y, acc, mine, theirs),
+                None => x.collect_keys(acc, mine),
+            }
+            acc.pop();
+        }
+        for (c, y) in ys.iter().filter(|(c, _)| !xs.contains_key(c)) {
+            acc.push(*c);
+            y.collect_keys(acc, theirs);
+            acc.pop();
+        }
+    }
+
+    /// Returns whether the trie holds at most `max_keys` keys.
+    pub fn fits_within(&self, max_keys: usize) -> bool {
+        self.count_terminals() <= max_keys
+    }
+
+    /// Returns the longest key that is a prefix of `query`, or `None` when no key is. Unlike
+    /// [`TNode::longest_prefix`], which returns the matched span as `""` when nothing
+    /// matches, this tells a stored empty key apart from no match at all.
+    pub fn longest_terminal_prefix(&self, query: &str) -> Option<String> {
+        let ends = std::iter::once(0).chain(query.char_indices().map(|(i, c)| i + c.len_utf8()));
+        self.path_nodes(query)
+            .zip(ends)
+            .filter(|(n, _)| n.is_terminal())
+            .last()
+            .map(|(_, end)| query[..end].to_string())
+    }
+
+    /// Writes the key set without contents in a LOUDS encoding: the node count as a `u32`
+    /// LE, then for each node in breadth-first order a `1` bit per child followed by a `0`,
+    /// then one terminal bit per node, then the edge labels as UTF-8 in the same order.
+    /// Bit runs are packed most significant bit first and padded to a byte.
+    pub fn serialize_keys_only<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        let mut order: Vec<&Self> = vec![];
+        let mut labels = String::new();
+        if !matches!(self, TNode::Empty) {
+            order.push(self);
+        }
+        let mut i = 0;
+        while let Some(n) = order.get(i) {
+            if let TNode::Node(node) = n {
+                for (c, child) in node.children.iter() {
+                    labels.push(*c);
+                    order.push(child);
+                }
+            }
+            i += 1;
+        }
+        let count = u32::try_from(order.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many nodes"))?;
+        let mut louds = vec![];
+        for n in &order {
+            let children = match n {
+                TNode::Node(node) => node.children.len(),
+                _ => 0,
+            };
+            louds.extend(std::iter::repeat_n(true, children));
+            louds.push(false);
+        }
+        let terminals: Vec<bool> = order.iter().map(|n| n.is_terminal()).collect();
+        w.write_all(&count.to_le_bytes())?;
+        w.write_all(&pack_bits(&louds))?;
+        w.write_all(&pack_bits(&terminals))?;
+        w.write_all(labels.as_bytes())
+    }
+
+    /// Returns the pairs of keys at edit distance 1 (one char inserted, removed or replaced),
+    /// each pair sorted and listed once. Every key is looked up with a bounded edit-distance
+    /// walk that drops branches as soon as they are two edits away.
+    pub fn near_duplicate_pairs(&self) -> Vec<(String, String)> {
+        let mut pairs = vec![];
+        for (key, _) in self.terminals() {
+            let query: Vec<char> = key.chars().collect();
+            let row: Vec<usize> = (0..=query.len()).collect();
+            let mut close = vec![];
+            self.within_distance_fn(&query, &row, 1, &mut String::new(), &mut close);
+            pairs.extend(
+                close
+                    .into_iter()
+                    .filter(|k| *k > key)
+                    .map(|k| (key.clone(), k)),
+            );
+        }
+        pairs
+    }
+
+    /// Collects keys within `max` edits of `query`, `row` holding the edit distances between
+    /// the current prefix and every prefix of `query`.
+    fn within_distance_fn(
+        &self,
+        query: &[char],
+        row: &[usize],
+        max: usize,
+        acc: &mut String,
+        out: &mut Vec<String>,
+    ) {
+        if self.is_terminal() && row[query.len()] <= max {
+            out.push(acc.clone());
+        }
+        if let TNode::Node(node) = self {
+            for (c, child) in node.children.iter() {
+                let mut next = vec![row[0] + 1];
+                for (i, q) in query.iter().enumerate() {
+                    let replace = row[i] + (q != c) as usize;
+                    next.push(replace.min(row[i + 1] + 1).min(next[i] + 1));
+                }
+                if next.iter().min().is_some_and(|d| *d <= max) {
+                    acc.push(*c);
+                    child.within_distance_fn(query, &next, max, acc, out);
+                    acc.pop();
+                }
+            }
+        }
+    }
+
+    /// Returns the content of key `s`, or `default` when the key is missing or has no
+    /// content.
+    pub fn get_or<'b>(&'b self, s: &str, default: &'b T) -> &'b T {
+        self.get(s).unwrap_or(default)
+    }
+
+    /// Lazily visits every node level by level, yielding its prefix and whether it is
+    /// terminal. The breadth-first counterpart of [`TNode::walk`].
+    pub fn bfs(&self) -> impl Iterator<Item = (String, bool)> + '_ {
+        let mut queue = VecDeque::new();
+        if !matches!(self, TNode::Empty) {
+            queue.push_back((String::new(), self));
+        }
+        std::iter::from_fn(move || {
+            let (prefix, n) = queue.pop_front()?;
+            if let TNode::Node(node) = n {
+                for (c, child) in node.children.iter() {
+                    queue.push_back((format!("{}{}", prefix, c), child));
+                }
+            }
+            Some((prefix, n.is_terminal()))
+        })
+    }
+
+    /// Collapses consecutive sorted keys with equal contents into `(first_key, last_key,
+    /// content)` runs. Keys without content are left out and end the current run.
+    pub fn run_length_values(&self) -> Vec<(String, String, T)>
+    where
+        T: Clone + PartialEq,
+    {
+        let mut runs: Vec<(String, String, T)> = vec![];
+        let mut open = false;
+        for (key, content) in self.entries() {
+            match content {
+                Some(v) if open && runs.last().is_some_and(|r| r.2 == *v) => {
+                    runs.last_mut().unwrap().1 = key;
+                }
+                Some(v) => {
+                    runs.push((key.clone(), key, v.clone()));
+                    open = true;
+                }
+                None => open = false,
+            }
+        }
+        runs
+    }
+
+    /// Consumes the trie into its sorted `(key, content)` pairs, counting the keys first to
+    /// allocate the vector once. Contents are moved out, so nothing is cloned.
+    pub fn into_sorted_vec(self) -> Vec<(String, Option<T>)> {
+        let mut out = Vec::with_capacity(self.count_terminals());
+        self.into_entries_fn(&mut String::new(), &mut out);
+        out
+    }
+
+    /// Returns the key starting with `prefix` that has the smallest content, the smallest
+    /// key winning ties. Keys without content are ignored.
+    pub fn min_value_in_prefix(&self, prefix: &str) -> Option<(String, &T)>
+    where
+        T: Ord,
+    {
+        self.find(prefix, false)?
+            .terminals()
+            .into_iter()
+            .filter_map(|(k, n)| Some((format!("{}{}", prefix, k), n.content().as_ref()?)))
+            .min_by_key(|(_, v)| *v)
+    }
+
+    /// Describes how `self` and `other` differ, in key order: keys only in one of them and
+    /// keys whose contents differ. At most 10 differences are listed, followed by a count of
+    /// the remaining ones. Empty when the tries are equal.
+    pub fn diff(&self, other: &TNode<T, M>) -> Vec<String>
+    where
+        T: PartialEq,
+    {
+        const SHOWN: usize = 10;
+        let (mine, theirs) = (self.entries(), other.entries());
+        let (mut i, mut j) = (0, 0);
+        let mut diffs = vec![];
+        while i < mine.len() || j < theirs.len() {
+            let order = match (mine.get(i), theirs.get(j)) {
+                (Some(a), Some(b)) => a.0.cmp(&b.0),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => {
+                    diffs.push(format!("{:?} only in self", mine[i].0));
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    diffs.push(format!("{:?} only in other", theirs[j].0));
+                    j += 1;
+                }
+                Ordering::Equal => {
+                    if mine[i].1 != theirs[j].1 {
+                        diffs.push(format!("{:?} has different contents", mine[i].0));
+                    }
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        if diffs.len() > SHOWN {
+            let more = diffs.len() - SHOWN;
+            diffs.truncate(SHOWN);
+            diffs.push(format!("... and {} more", more));
+        }
+        diffs
+    }
+
+    /// Compares the keys against a sorted slice in a single merge pass, returning
+    /// `(added, removed)`: keys only in `sorted`, and keys only in the trie.
+    pub fn delta_against_sorted(&self, sorted: &[&str]) -> (Vec<String>, Vec<String>) {
+        let mut keys = vec![];
+        self.collect_keys(&mut String::new(), &mut keys);
+        let (mut added, mut removed) = (vec![], vec![]);
+        let (mut i, mut j) = (0, 0);
+        while i < keys.len() || j < sorted.len() {
+            if j > 0 && sorted.get(j) == sorted.get(j - 1) {
+                j += 1;
+                continue;
+            }
+            let order = match (keys.get(i), sorted.get(j)) {
+                (Some(a), Some(b)) => a.as_str().cmp(b),
+                (Some(_), None) => Ordering::Less,
+                _ => Ordering::Greater,
+            };
+            match order {
+                Ordering::Less => {
+                    removed.push(keys[i].clone());
+                    i += 1;
+                }
+                Ordering::Greater => {
+                    added.push(sorted[j].to_string());
+                    j += 1;
+                }
+                Ordering::Equal => {
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+        (added, removed)
+    }
+
+    /// Re-roots the trie under a chain of nodes spelling `prefix`, so every key `k` becomes
+    /// `prefix + k` and the content of the empty key moves to `prefix`.
+    pub fn add_prefix(&mut self, prefix: &str) {
+        if prefix.is_empty() || matches!(self, TNode::Empty) {
+            return;
+        }
+        let version = self.snapshot_version() + 1;
+        let mut below = mem::replace(self, TNode::Empty);
+        if let TNode::Node(node) = &mut below {
+            node.suffix_index = None;
+        }
+        for c in prefix.chars().rev() {
+            let mut parent = TNode::Empty;
+            parent.make_node();
+            if let TNode::Node(node) = &mut parent {
+                node.terminals = below.count_terminals();
+                node.children.insert(c, below);
+                node.version = version;
+                node.max_version = version;
+            }
+            below = parent;
+        }
+        *self = below;
+    }
+
+    /// Walks down to `prefix` once and returns a handle for repeated lookups under it, or
+    /// `None` when no node has that prefix.
+    pub fn pin_subtree(&self, prefix: &str) -> Option<SubtrieHandle<'_, T, M>> {
+        Some(SubtrieHandle {
+            prefix: prefix.to_string(),
+            node: self.find(prefix, false)?,
+        })
+    }
+
+    /// Adds `s` and, when the trie then holds more than `max_keys` keys, evicts the last key
+    /// in sorted order (possibly `s` itself) and returns it. An existing key is left as is.
+    pub fn add_bounded(&mut self, s: &str, cont: Option<T>, max_keys: usize) -> Option<String> {
+        let _ = self.add(s, cont);
+        if self.fits_within(max_keys) {
+            return None;
+        }
+        let last = self.last_key()?;
+        self.remove(&last, false);
+        Some(last)
+    }
+
+    /// Returns the greatest key, following the last child down from the root.
+    fn last_key(&self) -> Option<String> {
+        let (mut key, mut last) = (String::new(), None);
+        let mut cur = self;
+        loop {
+            if cur.is_terminal() {
+                last = Some(key.clone());
+            }
+            match cur {
+                TNode::Node(node) if !node.children.is_empty() => {
+                    let (c, child) = node.children.iter().next_back().unwrap();
+                    key.push(*c);
+                    cur = child;
+                }
+                _ => return last,
+            }
+        }
+    }
+
+    /// Returns the sorted keys fully matching the regular expression `pattern`. The pattern
+    /// is run as an anchored DFA along the trie, so branches no match can go through are
+    /// skipped; patterns the DFA cannot handle fall back to testing every key.
+    #[cfg(feature = "regex")]
+    pub fn regex_search(&self, pattern: &str) -> Result<Vec<String>, regex::Error> {
+        use regex_automata::dfa::{dense, Automaton};
+        use regex_automata::util::start;
+        use regex_automata::{Anchored, MatchKind};
+
+        let full = regex::Regex::new(&format!("^(?:{})$", pattern))?;
+        let anchored = start::Config::new().anchored(Anchored::Yes);
+        // with leftmost-first semantics the DFA stops at the first match, so "a|ab" would
+        // never reach "ab"; keys need every match that can end at them
+        let dfa = dense::Builder::new()
+            .configure(dense::Config::new().match_kind(MatchKind::All))
+            .build(pattern)
+            .ok();
+        let sid = dfa.as_ref().and_then(|d| d.start_state(&anchored).ok());
+        let (Some(dfa), Some(sid)) = (&dfa, sid) else {
+            return Ok(self
+                .terminals()
+                .into_iter()
+                .map(|(k, _)| k)
+                .filter(|k| full.is_match(k))
+                .collect());
+        };
+        let mut out = vec![];
+        self.regex_fn(dfa, sid, &mut String::new(), &mut out);
+        Ok(out)
+    }
+
+    #[cfg(feature = "regex")]
+    fn regex_fn<A: regex_automata::dfa::Automaton>(
+        &self,
+        dfa: &A,
+        sid: regex_automata::util::primitives::StateID,
+        acc: &mut String,
+        out: &mut Vec<String>,
+    ) {
+        if self.is_terminal() && dfa.is_match_state(dfa.next_eoi_state(sid)) {
+            out.push(acc.clone());
+        }
+        if let TNode::Node(node) = self {
+            for (c, child) in node.children.iter() {
+                let mut next = sid;
+                for b in c.encode_utf8(&mut [0; 4]).bytes() {
+                    next = dfa.next_state(next, b);
+                }
+                if dfa.is_dead_state(next) {
+                    continue;
+                }
+                acc.push(*c);
+                child.regex_fn(dfa, next, acc, out);
+                acc.pop();
+            }
+        }
+    }
+
+    /// Returns the chars that can follow `prefix` on the way to some key, sorted, or nothing
+    /// when no key starts with `prefix`.
+    pub fn next_chars(&self, prefix: &str) -> Vec<char> {
+        match self.find(prefix, false) {
+            Some(TNode::Node(node)) => node.children.keys().copied().collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Predicts how adding `s` would change the trie's structure, without adding it.
+    /// Positions count chars; there is no chain to extend at the root, so a key growing
+    /// out of an empty trie is a new branch at 0.
+    pub fn insert_shape(&self, s: &str) -> InsertShape {
+        let (depth, node) = self
+            .path_nodes(s)
+            .enumerate()
+            .last()
+            .expect("the root is always on the path");
+        let len = s.chars().count();
+        if depth == len && node.is_terminal() {
+            InsertShape::AlreadyPresent
+        } else if 0 < depth && depth < len && node.is_childless() {
+            InsertShape::ExtendsChain { shared: depth }
+        } else {
+            InsertShape::NewBranch { at: depth }
+        }
+    }
+
+    /// Yields the sorted keys that have content, together with it.
+    pub fn populated_entries(&self) -> impl Iterator<Item = (String, &T)> {
+        self.entries()
+            .into_iter()
+            .filter_map(|(k, c)| Some((k, c.as_ref()?)))
+    }
+
+    /// Returns the longest prefix with at least `min_keys` keys starting with it, the
+    /// smallest one on ties, or `None` when the whole trie has fewer keys. Only children that
+    /// still hold `min_keys` keys are descended into.
+    pub fn frequent_prefix(&self, min_keys: usize) -> Option<String> {
+        if self.count_terminals() < min_keys {
+            return None;
+        }
+        let mut best = String::new();
+        let mut best_depth = 0;
+        let mut stack = vec![(String::new(), 0, self)];
+        while let Some((prefix, depth, n)) = stack.pop() {
+            if depth > best_depth {
+                best_depth = depth;
+                best = prefix.clone();
+            }
+            if let TNode::Node(node) = n {
+                for (c, child) in node.children.iter().rev() {
+                    if child.count_terminals() >= min_keys {
+                        let mut p = prefix.clone();
+                        p.push(*c);
+                        stack.push((p, depth + 1, child));
+                    }
+                }
+            }
+        }
+        Some(best)
+    }
+
+    /// Returns how many leading chars `a` and `b` share along a path of the trie, 0 when
+    /// they part at the root.
+    pub fn divergence_depth(&self, a: &str, b: &str) -> usize {
+        self.deepest_common_node(&[a, b])
+            .map_or(0, |prefix| prefix.chars().count())
+    }
+
+    /// Returns a membership-only copy with the same keys, every key holding `Some(())`, while
+    /// `self` keeps its payloads. Node versions are kept; metadata is not.
+    pub fn key_set(&self) -> TNode<()> {
+        let unit = |is_terminal: bool| is_terminal.then_some(());
+        match self {
+            TNode::Empty => TNode::Empty,
+            TNode::Leaf(leaf) => TNode::Leaf(Leaf {
+                content: unit(leaf.is_terminal),
+                is_terminal: leaf.is_terminal,
+                version: leaf.version,
+                max_version: leaf.max_version,
+                metadata: (),
+            }),
+            TNode::Node(node) => TNode::Node(Node {
+                content: unit(node.is_terminal),
+                children: node
+                    .children
+                    .iter()
+                    .map(|(k, v)| (*k, v.key_set()))
+                    .collect(),
+                is_terminal: node.is_terminal,
+                version: node.version,
+                max_version: node.max_version,
+                metadata: (),
+                suffix_index: None,
+                terminals: node.terminals,
+            }),
+        }
+    }
+
+    /// Returns, for each depth from 1 down, the char labelling the most nodes at that depth
+    /// and how many nodes it labels, the smallest char winning ties.
+    pub fn dominant_chars(&self) -> Vec<(char, usize)> {
+        let mut dominant = vec![];
+        let mut level: Vec<&Self> = vec![self];
+        loop {
+            let mut counts: BTreeMap<char, usize> = BTreeMap::new();
+            let mut next = vec![];
+            for n in level {
+                if let TNode::Node(node) = n {
+                    for (c, child) in node.children.iter() {
+                        *counts.entry(*c).or_default() += 1;
+                        next.push(child);
+                    }
+                }
+            }
+            let Some(best) = counts
+                .into_iter()
+                .reduce(|a, b| if b.1 > a.1 { b } else { a })
+            else {
+                return dominant;
+            };
+            dominant.push(best);
+            level = next;
+        }
+    }
+
+    /// Returns the content of `key`, or `None` when `key` is not stored or has no content.
+    pub fn get(&self, key: &str) -> Option<&T> {
+        match self.find(key, true)? {
+            TNode::Leaf(leaf) => leaf.content.as_ref(),
+            TNode::Node(node) => node.content.as_ref(),
+            TNode::Empty => None,
+        }
+    }
+
+    /// Returns a mutable reference to the content of `key`, so it can be updated in place.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        match self.node_mut(key)? {
+            TNode::Leaf(leaf) if leaf.is_terminal => leaf.content.as_mut(),
+            TNode::Node(node) if node.is_terminal => node.content.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Lazily yields mutable references to the contents of the keys starting with `prefix`,
+    /// in key order. The subtree is walked once, with no lookup per key.
+    pub fn values_mut_prefix<'b>(
+        &'b mut self,
+        prefix: &str,
+    ) -> impl Iterator<Item = &'b mut T> + 'b {
+        let mut pending = self.node_mut(prefix);
+        let mut stack: Vec<btree_map::ValuesMut<'b, char, Self>> = vec![];
+        std::iter::from_fn(move || loop {
+            let n = match pending.take() {
+                Some(n) => n,
+                None => match stack.last_mut()?.next() {
+                    Some(n) => n,
+                    None => {
+                        stack.pop();
+                        continue;
+                    }
+                },
+            };
+            let content = match n {
+                TNode::Empty => None,
+                TNode::Leaf(leaf) => leaf.is_terminal.then_some(&mut leaf.content),
+                TNode::Node(node) => {
+                    stack.push(node.children.values_mut());
+                    node.is_terminal.then_some(&mut node.content)
+                }
+            };
+            if let Some(c) = content.and_then(Option::as_mut) {
+                return Some(c);
+            }
+        })
+    }
+
+    /// Checks that [`TNode::find`] and [`TNode::longest_prefix_opts`] agree on each query:
+    /// when `find` matches the whole query, the longest prefix must be the query itself, and
+    /// the other way round. Returns the first disagreement found.
+    pub fn self_test(&self, queries: &[&str]) -> Result<(), String> {
+        for q in queries {
+            for must_be_terminal in [false, true] {
+                let found = self.find(q, must_be_terminal).is_some();
+                self.check_find_agrees(q, must_be_terminal, found)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Also run by `find` in debug builds, with what it found, so it must not call `find`.
+    fn check_find_agrees(
+        &self,
+        query: &str,
+        must_be_terminal: bool,
+        found: bool,
+    ) -> Result<(), String> {
+        let opts = PrefOptions {
+            must_be_terminal,
+            ..Default::default()
+        };
+        let prefix = self.longest_prefix_opts(query, opts);
+        // an empty prefix also stands for no match, so it proves nothing either way
+        let full = prefix == query && !query.is_empty();
+        if found && prefix != query || !found && full {
+            return Err(format!(
+                "query {:?} (must_be_terminal: {}): find {} but longest prefix is {:?}",
+                query,
+                must_be_terminal,
+                if found { "matches" } else { "does not match" },
+                prefix
+            ));
+        }
+        Ok(())
+    }
+
+    /// Lazily yields, in sorted order, the keys that are not a prefix of another key. The
+    /// streaming counterpart of [`TNode::leaf_keys`].
+    pub fn maximal_keys(&self) -> impl Iterator<Item = String> + '_ {
+        let mut stack = vec![(String::new(), self)];
+        std::iter::from_fn(move || {
+            while let Some((prefix, n)) = stack.pop() {
+                if n.is_terminal() && n.count_terminals() == 1 {
+                    return Some(prefix);
+                }
+                if let TNode::Node(node) = n {
+                    for (c, child) in node.children.iter().rev() {
+                        if child.count_terminals() > 0 {
+                            stack.push((format!("{}{}", prefix, c), child));
+                        }
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    /// Lazily yields every stored key in sorted order, including `""` when the root is
+    /// terminal.
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.terminal_nodes().map(|(k, _)| k)
+    }
+
+    /// Lazily yields, in sorted order, the full keys starting with `prefix`, `prefix`
+    /// itself included when stored. Nothing is yielded when no node has that prefix.
+    pub fn iter_prefix(&self, prefix: &str) -> impl Iterator<Item = String> + '_ {
+        let prefix = prefix.to_string();
+        self.find(&prefix, false)
+            .into_iter()
+            .flat_map(|n| n.terminal_nodes())
+            .map(move |(k, _)| format!("{}{}", prefix, k))
+    }
+
+    /// Maps each lowercased key to the stored keys folding to it, in sorted order, for
+    /// case-insensitive lookups without a second trie.
+    pub fn case_folded_index(&self) -> BTreeMap<String, Vec<String>> {
+        let mut index: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        for key in self.keys() {
+            index.entry(key.to_lowercase()).or_default().push(key);
+        }
+        index
+    }
+
+    /// Lazily yields every key in sorted order with its content, skipping keys stored
+    /// without one.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &T)> + '_ {
+        self.terminal_nodes()
+            .filter_map(|(k, n)| n.content().as_ref().map(|c| (k, c)))
+    }
+
+    /// Lazily yields the contents in key order, skipping keys stored without one. No keys
+    /// are built along the way.
+    pub fn values(&self) -> impl Iterator<Item = &T> + '_ {
+        let mut stack = vec![self];
+        std::iter::from_fn(move || {
+            while let Some(n) = stack.pop() {
+                if let TNode::Node(node) = n {
+                    stack.extend(node.children.values().rev());
+                }
+                if n.is_terminal() {
+                    if let Some(c) = n.content() {
+                        return Some(c);
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    fn terminal_nodes(&self) -> impl Iterator<Item = (String, &Self)> + '_ {
+        let mut stack = vec![(String::new(), self)];
+        std::iter::from_fn(move || {
+            while let Some((prefix, n)) = stack.pop() {
+                if let TNode::Node(node) = n {
+                    for (c, child) in node.children.iter().rev() {
+                        stack.push((format!("{}{}", prefix, c), child));
+                    }
+                }
+                if n.is_terminal() {
+                    return Some((prefix, n));
+                }
+            }
+            None
+        })
+    }
+}
+
+impl TNode<()> {
+    /// Builds a membership-only trie from keys sorted in ascending order.
+    ///
+    /// Keys sharing a prefix are contiguous in a sorted slice, so each node is built once from
+    /// its group of suffixes instead of re-descending from the root for every key. Duplicate
+    /// keys are ignored.
+    pub fn from_sorted_strs(keys: &[&str]) -> Self {
+        debug_assert!(keys.windows(2).all(|w| w[0] <= w[1]), "keys must be sorted");
+        Self::build_sorted(keys)
+    }
+
+    /// Reads keys written by [`TNode::to_compact_text`].
+    pub fn from_compact_text(text: &str) -> Result<Self, InvalidCompactText> {
+        let mut t = TNode::new();
+        let mut prev = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let err = InvalidCompactText { line: i + 1 };
+            let (shared, suffix) = line.split_once(':').ok_or(err.clone())?;
+            let shared: usize = shared.parse().map_err(|_| err.clone())?;
+            let mut key: String = prev.chars().take(shared).collect();
+            if key.chars().count() != shared {
+                return Err(err);
+            }
+            key.push_str(suffix);
+            t.add(&key, Some(())).map_err(|_| err)?;
+            prev = key;
+        }
+        Ok(t)
+    }
+
+    /// Reads a key set written by [`TNode::serialize_keys_only`].
+    pub fn deserialize_keys_only<R: Read>(r: &mut R) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut count = [0; 4];
+        r.read_exact(&mut count)?;
+        let count = u32::from_le_bytes(count) as usize;
+        if count == 0 {
+            return Ok(TNode::Empty);
+        }
+        let mut louds = vec![0; (2 * count - 1).div_ceil(8)];
+        r.read_exact(&mut louds)?;
+        let mut terminals = vec![0; count.div_ceil(8)];
+        r.read_exact(&mut terminals)?;
+        let mut labels = String::new();
+        r.read_to_string(&mut labels)?;
+        let mut labels = labels.chars();
+        let bit = |bytes: &[u8], i: usize| bytes[i / 8] & (0x80 >> (i % 8)) != 0;
+
+        let mut keys = vec![String::new()];
+        let mut pos = 0;
+        for parent in 0..count {
+            if parent >= keys.len() {
+                return Err(invalid("node count does not match the encoding"));
+            }
+            while pos < 2 * count - 1 && bit(&louds, pos) {
+                let c = labels.next().ok_or(invalid("missing edge label"))?;
+                keys.push(format!("{}{}", keys[parent], c));
+                pos += 1;
+            }
+            pos += 1;
+        }
+        if keys.len() != count || labels.next().is_some() {
+            return Err(invalid("node count does not match the encoding"));
+        }
+        let mut terminal_keys: Vec<&str> = keys
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| bit(&terminals, *i))
+            .map(|(_, k)| k.as_str())
+            .collect();
+        terminal_keys.sort();
+        Ok(Self::build_sorted(&terminal_keys))
+    }
+
+    fn build_sorted(suffixes: &[&str]) -> Self {
+        if suffixes.is_empty() {
+            return TNode::Empty;
+        }
+        let is_terminal = suffixes[0].is_empty();
+        let content = is_terminal.then_some(());
+        let rest: Vec<&str> = suffixes
+            .iter()
+            .copied()
+            .skip_while(|s| s.is_empty())
+            .collect();
+        if rest.is_empty() {
+            return TNode::Leaf(Leaf {
+                content,
+                is_terminal,
+                version: is_terminal as u64,
+                max_version: 1,
+                metadata: (),
+            });
+        }
+
+        let mut children = BTreeMap::new();
+        let mut start = 0;
+        while start < rest.len() {
+            let c = rest[start].chars().next().unwrap();
+            let end = start
+                + rest[start..]
+                    .iter()
+                    .take_while(|s| s.starts_with(c))
+                    .count();
+            let group: Vec<&str> = rest[start..end]
+                .iter()
+                .map(|s| &s[c.len_utf8()..])
+                .collect();
+            children.insert(c, Self::build_sorted(&group));
+            start = end;
+        }
+        let terminals = is_terminal as usize
+            + children
+                .values()
+                .map(|c: &Self| c.count_terminals())
+                .sum::<usize>();
+        TNode::Node(Node {
+            content,
+            children,
+            is_terminal,
+            version: is_terminal as u64,
+            max_version: 1,
+            metadata: (),
+            suffix_index: None,
+            terminals,
+        })
+    }
+}
+
+impl TNode<String> {
+    /// Reads one `key<delimiter>value` entry per line, splitting on the first delimiter;
+    /// lines without one add the key without content. A key repeated on a later line is an
+    /// [`io::ErrorKind::InvalidData`] error.
+    pub fn from_csv<R: BufRead>(r: R, delimiter: char) -> io::Result<Self> {
+        let mut t = TNode::new();
+        for (i, line) in r.lines().enumerate() {
+            let line = line?;
+            let (key, value) = match line.split_once(delimiter) {
+                Some((k, v)) => (k, Some(v.to_string())),
+                None => (line.as_str(), None),
+            };
+            t.add(key, value).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("duplicate key on line {}", i + 1),
+                )
+            })?;
+        }
+        Ok(t)
+    }
+}
+
+impl<M: Default> TNode<usize, M> {
+    /// Counts one more occurrence of `s`, starting at 1 when `s` is absent or stored without
+    /// a count.
+    pub fn add_counting(&mut self, s: &str) {
+        if let Some(count) = self.get_mut(s) {
+            *count += 1;
+            return;
+        }
+        match self.node_mut(s) {
+            Some(TNode::Leaf(leaf)) if leaf.is_terminal => leaf.content = Some(1),
+            Some(TNode::Node(node)) if node.is_terminal => node.content = Some(1),
+            // `s` is not stored, so this cannot fail
+            _ => {
+                let _ = self.add(s, Some(1));
+            }
+        }
+    }
+}
+
+impl<T: Display, M: Default> TNode<T, M> {
+    pub fn pp(&self, print_content: bool) -> String {
+        self.pp_fn(0, if print_content { Some("  ") } else { None })
+    }
+
+    /// Like `pp(true)`, but pads the keys so that all contents start in the same column.
+    pub fn pp_aligned(&self) -> String {
+        let raw = self.pp_fn(0, Some("\0"));
+        let lines: Vec<(&str, Option<&str>)> = raw
+            .lines()
+            .map(|l| match l.split_once('\0') {
+                Some((key, cont)) => (key, Some(cont)),
+                None => (l, None),
+            })
+            .collect();
+        let width = lines
+            .iter()
+            .filter(|(_, cont)| cont.is_some())
+            .map(|(key, _)| key.chars().count())
+            .max()
+            .unwrap_or(0);
+
+        let mut res = String::new();
+        for (key, cont) in lines {
+            res.push_str(key);
+            if let Some(cont) = cont {
+                res.push_str(&" ".repeat(width - key.chars().count() + 2));
+                res.push_str(cont);
+            }
+            res.push('\n');
+        }
+        res
+    }
+
+    fn pp_fn(&self, indent: u8, content_sep: Option<&str>) -> String {
+        let mut res = String::from("");
+        match &self {
+            TNode::Empty => {
+                res.push_str("[empty]\n");
+                res
+            }
+            TNode::Leaf { .. } => {
+                if let Some(sep) = content_sep {
+                    res.push_str(sep);
+                    res.push_str(self.to_string().as_str());
+                }
+                res.push('\n');
+                res
+            }
+            TNode::Node(node) => {
+                let iter = node.children.iter();
+
+                let child_count = node.children.len();
+
+                for (k, v) in iter {
+                    if node.is_terminal || child_count > 1 {
+                        if indent != 0 {
+                            res.push('\n');
+                        }
+                        res.push_str(&" ".repeat(indent.into()));
+                    }
+
+                    res.push(*k);
+                    res.push_str(v.pp_fn(indent + 1, content_sep).as_str());
+                }
+                res
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    #[test]
+    fn pretty_print() {
+        let t: TNode<u8> = TNode::Node(Node {
+            is_terminal: false,
+            version: 0,
+            max_version: 0,
+            metadata: (),
+            suffix_index: None,
+            terminals: 4,
+            content: None,
+            children: BTreeMap::from([
+                (
+                    'a',
+                    TNode::Node(Node {
+                        is_terminal: true,
+                        version: 0,
+                        max_version: 0,
+                        metadata: (),
+                        suffix_index: None,
+                        terminals: 2,
+                        content: None,
+                        children: BTreeMap::from([(
+                            'b',
+                            TNode::Node(Node {
+                                is_terminal: false,
+                                version: 0,
+                                max_version: 0,
+                                metadata: (),
+                                suffix_index: None,
+                                terminals: 1,
+                                content: None,
+                                children: BTreeMap::from([(
+                                    'c',
+                                    TNode::Leaf(Leaf {
                                         is_terminal: true,
-                                        content: &None,
+                                        version: 0,
+                                        max_version: 0,
+                                        metadata: (),
+                                        content: None,
                                     }),
                                 )]),
                             }),
@@ -445,198 +3071,2155 @@ mod tests {
                     'd',
                     TNode::Leaf(Leaf {
                         is_terminal: true,
-                        content: &None,
+                        version: 0,
+                        max_version: 0,
+                        metadata: (),
+                        content: None,
                     }),
                 ),
                 (
                     'e',
                     TNode::Leaf(Leaf {
                         is_terminal: true,
-                        content: &None,
+                        version: 0,
+                        max_version: 0,
+                        metadata: (),
+                        content: None,
                     }),
                 ),
             ]),
         });
-        assert_eq!(t.pp(false), "a\n bc\nd\ne\n")
+        assert_eq!(t.pp(false), "a\n bc\nd\ne\n")
+    }
+
+    #[test]
+    fn add_to_empty_trie() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        match t {
+            TNode::Node(node) => {
+                assert_eq!(node.content, None);
+                assert!(!node.is_terminal);
+                let subt = node.children.get(&'a').unwrap();
+                assert_eq!(subt.content(), &Some(1));
+                assert!(subt.is_terminal());
+            }
+            _ => panic!("t should be TNode::Node"),
+        }
+    }
+
+    #[test]
+    fn add_single_char_string() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("ab", Some(1)).unwrap();
+        t.add("c", Some(1)).unwrap();
+        t.add("d", Some(1)).unwrap();
+        assert_eq!(t.pp(false), "a\n b\nc\nd\n")
+    }
+
+    #[test]
+    fn contains_key() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        assert!(t.contains_key("a"));
+
+        t.add("abc", Some(2)).unwrap();
+        assert!(!t.contains_key("b"));
+        assert!(t.contains_key("abc"));
+    }
+
+    #[test]
+    fn show_content() {
+        let mut t = TNode::new();
+        assert_eq!(t.pp(true), "[empty]\n");
+
+        t.add("a", Some(1)).unwrap();
+        assert_eq!(t.pp(true), "a  (1)\n");
+
+        t.add("abc", Some(2)).unwrap();
+        assert_eq!(t.pp(true), "a\n bc  (2)\n");
+
+        t.add("d", Some(3)).unwrap();
+        assert_eq!(t.pp(true), "a\n bc  (2)\nd  (3)\n");
+
+        t.add("e", Some(4)).unwrap();
+        assert_eq!(t.pp(true), "a\n bc  (2)\nd  (3)\ne  (4)\n");
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(1)).unwrap();
+        let res = t.longest_prefix("this is more wo", false);
+        let expected: Vec<char> = "this is more wo".chars().collect();
+        assert_eq!(res.chars().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn longest_prefix_no_full_match() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(1)).unwrap();
+        let res = t.longest_prefix("this is weeks", false);
+        let expected: Vec<char> = "this is w".chars().collect();
+        assert_eq!(res.chars().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn longest_prefix_terminal() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(1)).unwrap();
+        let res = t.longest_prefix("this is more wo", true);
+        let expected: Vec<char> = "this is more".chars().collect();
+        assert_eq!(res.chars().collect::<Vec<_>>(), expected);
+    }
+
+    #[test]
+    fn longest_prefix_fail() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(1)).unwrap();
+        let res = t.longest_prefix("this is", true);
+        assert!(res.is_empty());
+    }
+
+    #[test]
+    fn find() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(2)).unwrap();
+        t.add("this is even more", Some(3)).unwrap();
+        let res = t.find("this is more", false).unwrap();
+        //let expected: Vec<char> = "this is more".chars().collect();
+        assert_eq!(res.content().unwrap(), 2)
+    }
+    #[test]
+    fn find_terminal() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(2)).unwrap();
+        t.add("this is even more", Some(3)).unwrap();
+        let res = t.find("this is more", true).unwrap();
+        //let expected: Vec<char> = "this is more".chars().collect();
+        assert_eq!(res.content().unwrap(), 2);
+    }
+    #[test]
+    fn find_terminal_fail() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is even more", Some(1)).unwrap();
+        let pref = t.find("this is more wo", true);
+        assert!(pref.is_none())
+    }
+
+    #[test]
+    fn remove() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        t.add("abcd", Some(3)).unwrap();
+
+        assert!(!t.remove("ab", false));
+        assert!(t.contains_key("a"));
+        assert!(t.contains_key("abc"));
+        assert!(t.contains_key("abcd"));
+
+        assert!(t.remove("abc", true));
+        assert!(t.contains_key("a"));
+        assert!(!t.contains_key("abc"));
+        assert!(!t.contains_key("abcd"));
+
+        assert!(t.remove("a", false));
+        assert!(t.is_empty());
+    }
+
+    #[test]
+    fn remove_non_terminal() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        t.remove("abc", false);
+        println!("{}", t.pp(true));
+        let expected = "a\n";
+        assert_eq!(t.pp(false), expected);
+    }
+    #[test]
+    fn remove_subtree() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        t.remove("ab", true);
+        println!("{}", t.pp(true));
+        let expected = "a\n";
+        assert_eq!(t.pp(false), expected);
+    }
+    #[test]
+    fn remove_non_existing() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        let expected = t.pp(false);
+        t.remove("xyz", true);
+        println!("{}", t.pp(true));
+        assert_eq!(t.pp(false), expected);
+    }
+
+    #[test]
+    fn shard() {
+        let mut t = TNode::new();
+        for (i, k) in ["a", "ab", "abc", "b", "ba", "c", "cd", "d", "e", "f"]
+            .iter()
+            .enumerate()
+        {
+            t.add(k, if i % 2 == 0 { Some(1) } else { Some(2) })
+                .unwrap();
+        }
+        let shards = t.shard(3);
+        assert_eq!(shards.len(), 3);
+        let sizes: Vec<usize> = shards.iter().map(|s| s.entries().len()).collect();
+        assert_eq!(sizes, vec![3, 3, 4]);
+        assert_eq!(sizes.iter().sum::<usize>(), t.entries().len());
+        assert!(shards[0].contains_key("abc"));
+        assert!(shards[1].contains_key("ba"));
+        assert!(shards[2].contains_key("f"));
+    }
+
+    #[test]
+    fn shard_more_than_keys() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        let shards = t.shard(2);
+        assert_eq!(shards.len(), 2);
+        assert!(!shards[0].contains_key("a"));
+        assert!(shards[1].contains_key("a"));
+    }
+
+    #[test]
+    fn unique_completion() {
+        let mut t = TNode::new();
+        t.add("commit", Some(1)).unwrap();
+        t.add("checkout", Some(2)).unwrap();
+        t.add("cherry-pick", Some(3)).unwrap();
+        assert_eq!(t.unique_completion("co"), Some("commit".to_owned()));
+        assert_eq!(t.unique_completion("chec"), Some("checkout".to_owned()));
+        assert_eq!(t.unique_completion("commit"), Some("commit".to_owned()));
+        assert_eq!(t.unique_completion("ch"), None);
+        assert_eq!(t.unique_completion("c"), None);
+        assert_eq!(t.unique_completion("push"), None);
+    }
+
+    #[test]
+    fn content_path() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("ab", None).unwrap();
+        t.add("abc", Some(3)).unwrap();
+        t.add("abcd", Some(4)).unwrap();
+        t.add("abd", Some(5)).unwrap();
+
+        let mut path = t.content_path("abcx");
+        assert_eq!(path.next(), Some(&1));
+        assert_eq!(path.next(), Some(&3));
+        assert_eq!(path.next(), None);
+
+        let all: Vec<_> = t.content_path("abcd").collect();
+        assert_eq!(all, vec![&1, &3, &4]);
+        assert_eq!(t.content_path("x").next(), None);
+    }
+
+    #[test]
+    fn balance_factor() {
+        let letters: Vec<char> = ('a'..='j').collect();
+        let keys: Vec<String> = letters
+            .iter()
+            .flat_map(|a| letters.iter().map(move |b| format!("{}{}", a, b)))
+            .collect();
+        let mut bushy = TNode::new();
+        for k in &keys {
+            bushy.add(k, Some(1)).unwrap();
+        }
+        assert!((bushy.balance_factor() - 1.0).abs() < 1e-9);
+
+        let mut chain = TNode::new();
+        let long = "abcdefghijklmnopqrstuvwxyz";
+        for i in 1..=long.len() {
+            chain.add(&long[..i], Some(1)).unwrap();
+        }
+        assert!(chain.balance_factor() > 10.0);
+        assert_eq!(TNode::<u8>::Empty.balance_factor(), 0.0);
+    }
+
+    #[test]
+    fn remove_shorter_than() {
+        let mut t = TNode::new();
+        t.add("", Some(0)).unwrap();
+        t.add("a", Some(1)).unwrap();
+        t.add("ab", Some(2)).unwrap();
+        t.add("abcd", Some(3)).unwrap();
+        t.add("xyz", Some(4)).unwrap();
+        t.add("q", Some(5)).unwrap();
+
+        let removed = t.remove_shorter_than(3);
+        assert_eq!(removed, vec!["", "a", "ab", "q"]);
+        assert!(!t.contains_key(""));
+        assert!(!t.contains_key("a"));
+        assert!(!t.contains_key("ab"));
+        assert!(!t.contains_key("q"));
+        assert!(t.contains_key("abcd"));
+        assert!(t.contains_key("xyz"));
+        assert_eq!(t.pp(false), "abcd\nxyz\n");
+    }
+
+    #[test]
+    fn remove_keeps_siblings() {
+        let mut t = TNode::new();
+        t.add("abx", Some(1)).unwrap();
+        t.add("aby", Some(2)).unwrap();
+        t.add("ab", Some(3)).unwrap();
+        t.add("abyz", Some(4)).unwrap();
+        assert!(t.remove("abx", false));
+        assert!(t.contains_key("aby"));
+        assert!(t.remove("aby", false));
+        assert!(t.contains_key("ab"));
+        assert!(t.contains_key("abyz"));
+    }
+
+    #[test]
+    fn leaf_keys() {
+        let mut t = TNode::new();
+        t.add("ab", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        t.add("abd", Some(3)).unwrap();
+        t.add("x", Some(4)).unwrap();
+        assert_eq!(t.leaf_keys(), vec!["abc", "abd", "x"]);
+        assert!(TNode::<u8>::Empty.leaf_keys().is_empty());
+    }
+
+    #[test]
+    fn prefix_keys() {
+        let mut t = TNode::new();
+        t.add("10.0", Some(1)).unwrap();
+        t.add("10.0.1", Some(2)).unwrap();
+        t.add("10.0.1.5", Some(3)).unwrap();
+        t.add("10.1", Some(4)).unwrap();
+        t.add("192.168", Some(5)).unwrap();
+        assert_eq!(t.prefix_keys(), vec!["10.0", "10.0.1"]);
+    }
+
+    #[test]
+    fn longest_prefix_opts() {
+        let mut t = TNode::new();
+        t.add("this is words", Some(1)).unwrap();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(1)).unwrap();
+        let queries = ["this is more wo", "this is weeks", "this is more weeks"];
+        let cases = [
+            (
+                (false, false, false),
+                ["this is more wo", "this is w", "this is more w"],
+            ),
+            ((true, false, false), ["this is more", "", "this is more"]),
+            ((false, true, false), ["this is more wo", "", ""]),
+            (
+                (false, false, true),
+                ["this is more", "this is w", "this is more"],
+            ),
+            ((true, true, false), ["", "", ""]),
+            ((true, false, true), ["this is more", "", "this is more"]),
+            ((false, true, true), ["this is more", "", ""]),
+            ((true, true, true), ["", "", ""]),
+        ];
+        for ((must_be_terminal, must_match_fully, prefer_terminal), expected) in cases {
+            let opts = PrefOptions {
+                must_be_terminal,
+                must_match_fully,
+                prefer_terminal,
+            };
+            for (q, e) in queries.iter().zip(expected) {
+                assert_eq!(t.longest_prefix_opts(q, opts), e, "{:?} {:?}", q, opts);
+            }
+        }
+    }
+
+    #[test]
+    fn longest_prefix_past_leaf() {
+        let mut t = TNode::new();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(1)).unwrap();
+        let res = t.longest_prefix_opts("this is more words and more", PrefOptions::default());
+        assert_eq!(res, "this is more words");
+    }
+
+    #[test]
+    fn summarize_ranges() {
+        let mut t = TNode::new();
+        for k in ["a1", "a2", "a3", "a4", "a5"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.summarize_ranges(), vec!["a[1-5]"]);
+
+        t.add("a7", Some(1)).unwrap();
+        t.add("b", Some(1)).unwrap();
+        t.add("bx", Some(1)).unwrap();
+        t.add("by", Some(1)).unwrap();
+        assert_eq!(t.summarize_ranges(), vec!["a[1-5]", "a7", "b", "b[x-y]"]);
+    }
+
+    #[test]
+    fn from_sorted_strs() {
+        let keys = ["", "a", "ab", "ab", "abc", "b", "ba", "été"];
+        let t = TNode::from_sorted_strs(&keys);
+        for k in keys {
+            assert!(t.contains_key(k));
+        }
+        assert!(!t.contains_key("bb"));
+        assert!(!t.contains_key("é"));
+        assert_eq!(t.entries().len(), 7);
+        assert_eq!(TNode::from_sorted_strs(&[]).entries().len(), 0);
+    }
+
+    #[test]
+    fn contains_key_below_terminal() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        assert!(!t.contains_key("ab"));
+        assert!(t.find("ab", true).is_none());
+        assert!(t.find("ab", false).is_some());
+    }
+
+    #[test]
+    fn shortest_prefix_value() {
+        let mut t = TNode::new();
+        t.add("/api/v1/users", Some(3)).unwrap();
+        t.add("/api", Some(1)).unwrap();
+        t.add("/api/v1", Some(2)).unwrap();
+        assert_eq!(t.shortest_prefix_value("/api/v1/users/42"), Some(&1));
+        assert_eq!(t.shortest_prefix_value("/api"), Some(&1));
+        assert_eq!(t.shortest_prefix_value("/ap"), None);
+        assert_eq!(t.shortest_prefix_value("/static"), None);
+    }
+
+    #[test]
+    fn match_rate() {
+        let mut t = TNode::new();
+        t.add("apple", Some(1)).unwrap();
+        t.add("app", Some(2)).unwrap();
+        t.add("banana", Some(3)).unwrap();
+        let queries = vec!["app", "appl", "apple", "banana", "cherry", ""];
+        assert_eq!(t.match_rate(queries), (3, 6));
+        assert_eq!(t.match_rate(Vec::<&str>::new()), (0, 0));
+    }
+
+    #[test]
+    fn entries_with_leaf_flag() {
+        let mut t = TNode::new();
+        t.add("src", Some(1)).unwrap();
+        t.add("src/lib.rs", Some(2)).unwrap();
+        t.add("src/trie.rs", Some(3)).unwrap();
+        t.add("Cargo.toml", Some(4)).unwrap();
+        assert_eq!(
+            t.entries_with_leaf_flag(),
+            vec![
+                ("Cargo.toml".to_owned(), true),
+                ("src".to_owned(), false),
+                ("src/lib.rs".to_owned(), true),
+                ("src/trie.rs".to_owned(), true),
+            ]
+        );
+    }
+
+    #[test]
+    fn drop_contents() {
+        let mut t = TNode::new();
+        t.add("a", Some("heavy")).unwrap();
+        t.add("abc", None).unwrap();
+        t.add("b", Some("payload")).unwrap();
+        let keys: Vec<String> = t.entries().into_iter().map(|(k, _)| k).collect();
+
+        let unit: TNode<()> = t.drop_contents();
+        let unit_entries = unit.entries();
+        assert_eq!(unit_entries.len(), keys.len());
+        for ((k, c), expected) in unit_entries.into_iter().zip(keys) {
+            assert_eq!(k, expected);
+            assert_eq!(c, &Some(()));
+        }
+        assert!(!unit.contains_key("ab"));
+    }
+
+    #[test]
+    fn add_reporting_shared() {
+        let mut t = TNode::new();
+        assert_eq!(t.add_reporting_shared("car", Some(1)).unwrap(), 0);
+        assert_eq!(t.add_reporting_shared("cart", Some(2)).unwrap(), 3);
+        assert_eq!(t.add_reporting_shared("cat", Some(3)).unwrap(), 2);
+        assert_eq!(t.add_reporting_shared("ca", Some(4)).unwrap(), 2);
+        assert_eq!(t.add_reporting_shared("dog", Some(5)).unwrap(), 0);
+        assert!(t.add_reporting_shared("cart", Some(6)).is_err());
+        assert!(t.contains_key("cart"));
+    }
+
+    #[test]
+    fn find_by_value() {
+        let mut t = TNode::new();
+        t.add("the", Some(120)).unwrap();
+        t.add("then", Some(8)).unwrap();
+        t.add("there", Some(45)).unwrap();
+        t.add("thorn", None).unwrap();
+        let frequent = t.find_by_value(|&n| n > 10);
+        assert_eq!(
+            frequent,
+            vec![("the".to_owned(), &120), ("there".to_owned(), &45)]
+        );
+        assert!(t.find_by_value(|&n| n > 1000).is_empty());
+    }
+
+    #[test]
+    fn pp_aligned() {
+        let mut t = TNode::new();
+        assert_eq!(t.pp_aligned(), "[empty]\n");
+
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        t.add("d", Some(3)).unwrap();
+        t.add("efgh", Some(4)).unwrap();
+        assert_eq!(t.pp(true), "a\n bc  (2)\nd  (3)\nefgh  (4)\n");
+        assert_eq!(t.pp_aligned(), "a\n bc   (2)\nd     (3)\nefgh  (4)\n");
+    }
+
+    #[test]
+    fn terminal_chain_keys() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("ab", Some(2)).unwrap();
+        t.add("abc", Some(3)).unwrap();
+        t.add("x", Some(4)).unwrap();
+        t.add("xyz", Some(5)).unwrap();
+        assert_eq!(t.terminal_chain_keys(), vec!["a", "ab", "abc", "x"]);
+    }
+
+    #[test]
+    fn longest_prefix_long_query() {
+        let mut t = TNode::new();
+        let key: String = "ab€".repeat(400);
+        t.add(&key, Some(1)).unwrap();
+        t.add(&key[..1000], Some(2)).unwrap();
+        let query = format!("{}xyz", key);
+        assert_eq!(t.longest_prefix_opts(&query, PrefOptions::default()), key);
+        let opts = PrefOptions {
+            must_be_terminal: true,
+            ..PrefOptions::default()
+        };
+        assert_eq!(t.longest_prefix_opts(&query[..1500], opts), &key[..1000]);
+    }
+
+    #[test]
+    fn longest_prefix_detailed() {
+        let mut t = TNode::new();
+        t.add("this is more", Some(1)).unwrap();
+        t.add("this is more words", Some(2)).unwrap();
+
+        let full = t.longest_prefix_detailed("this is more", false);
+        assert_eq!(full, Some(("this is more".to_owned(), true, true)));
+
+        let partial = t.longest_prefix_detailed("this is more wo", false);
+        assert_eq!(partial, Some(("this is more wo".to_owned(), false, true)));
+
+        let partial = t.longest_prefix_detailed("this is more weeks", true);
+        assert_eq!(partial, Some(("this is more".to_owned(), true, false)));
+
+        let diverged = t.longest_prefix_detailed("this is wrong", false);
+        assert_eq!(diverged, Some(("this is ".to_owned(), false, false)));
+
+        assert_eq!(t.longest_prefix_detailed("that", true), None);
+    }
+
+    #[test]
+    fn value_index() {
+        let mut t = TNode::new();
+        t.add("cat", Some("animal")).unwrap();
+        t.add("oak", Some("plant")).unwrap();
+        t.add("dog", Some("animal")).unwrap();
+        t.add("rock", None).unwrap();
+        let index = t.value_index();
+        assert_eq!(index.len(), 2);
+        assert_eq!(index[&"animal"], vec!["cat", "dog"]);
+        assert_eq!(index[&"plant"], vec!["oak"]);
+    }
+
+    #[test]
+    fn strict() {
+        let mut t = TNode::new();
+        assert!(t.add("", Some(0)).is_ok());
+        assert!(t.contains_key(""));
+
+        let mut s = TNode::strict();
+        assert!(matches!(s.add("", Some(0)), Err(StrictAddError::EmptyKey)));
+        assert!(s.add("a", Some(1)).is_ok());
+        assert!(matches!(
+            s.add("a", Some(2)),
+            Err(StrictAddError::KeyExists)
+        ));
+        assert!(s.contains_key("a"));
+        assert!(!s.contains_key(""));
+        assert!(s.into_inner().contains_key("a"));
+    }
+
+    #[test]
+    fn content_hash() {
+        let mut a = TNode::new();
+        a.add("one", Some(1)).unwrap();
+        a.add("two", Some(2)).unwrap();
+        a.add("three", None).unwrap();
+        let mut b = TNode::new();
+        b.add("three", None).unwrap();
+        b.add("two", Some(2)).unwrap();
+        b.add("one", Some(1)).unwrap();
+        assert_eq!(a.content_hash(), b.content_hash());
+
+        let mut c = TNode::new();
+        c.add("one", Some(1)).unwrap();
+        c.add("two", Some(3)).unwrap();
+        c.add("three", None).unwrap();
+        assert_ne!(a.content_hash(), c.content_hash());
+
+        let mut d = TNode::new();
+        d.add("one", Some(1)).unwrap();
+        d.add("two", Some(2)).unwrap();
+        assert_ne!(a.content_hash(), d.content_hash());
+    }
+
+    #[test]
+    fn keys_since() {
+        let mut t = TNode::new();
+        assert_eq!(t.snapshot_version(), 0);
+        t.add("apple", Some(1)).unwrap();
+        t.add("banana", Some(2)).unwrap();
+        let snapshot = t.snapshot_version();
+        assert!(t.keys_since(snapshot).is_empty());
+
+        t.add("app", Some(3)).unwrap();
+        t.add("cherry", Some(4)).unwrap();
+        assert!(t.snapshot_version() > snapshot);
+        assert_eq!(t.keys_since(snapshot), vec!["app", "cherry"]);
+        assert_eq!(t.keys_since(0).len(), 4);
+
+        let before_remove = t.snapshot_version();
+        t.remove("cherry", false);
+        assert!(t.snapshot_version() > before_remove);
+        t.add("date", Some(5)).unwrap();
+        assert_eq!(t.keys_since(snapshot), vec!["app", "date"]);
+        assert_eq!(t.keys_since(before_remove), vec!["date"]);
+    }
+
+    #[test]
+    fn covering_prefixes() {
+        let mut t = TNode::new();
+        t.add("/api", Some(1)).unwrap();
+        t.add("/api/v1", Some(2)).unwrap();
+        t.add("/api/v2/users", Some(3)).unwrap();
+        t.add("/static/css", Some(4)).unwrap();
+        t.add("/static/css/main.css", Some(5)).unwrap();
+        t.add("/static/js", Some(6)).unwrap();
+        assert_eq!(
+            t.covering_prefixes(),
+            vec!["/api", "/static/css", "/static/js"]
+        );
+
+        t.add("", Some(0)).unwrap();
+        assert_eq!(t.covering_prefixes(), vec![""]);
+    }
+
+    #[test]
+    fn tokens() {
+        let mut t = TNode::new();
+        t.add_tokens(&["new", "york"], Some(1)).unwrap();
+        t.add_tokens(&["new", "york", "city"], Some(2)).unwrap();
+        t.add_tokens(&["new", "jersey"], Some(3)).unwrap();
+        t.add_tokens(&["newark"], Some(4)).unwrap();
+        assert!(t.add_tokens(&["new", "york"], Some(5)).is_err());
+
+        let new = t.find_tokens(&["new"]).unwrap();
+        assert!(!new.is_terminal());
+        assert_eq!(new.entries().len(), 3);
+        assert_eq!(t.find_tokens(&["new", "york"]).unwrap().content(), &Some(1));
+        assert!(t.find_tokens(&["new", "yo"]).is_none());
+        assert!(t.find_tokens(&["york"]).is_none());
+        assert_eq!(t.find_tokens(&["newark"]).unwrap().content(), &Some(4));
+    }
+
+    #[test]
+    fn minimization_estimate() {
+        let mut t = TNode::new();
+        for k in ["tap", "taps", "top", "tops", "lap", "laps"] {
+            t.add(k, Some(())).unwrap();
+        }
+        let (nodes, minimized) = t.minimization_estimate();
+        assert_eq!(nodes, 12);
+        assert_eq!(minimized, 6);
+
+        let mut u = TNode::new();
+        u.add("ab", Some(1)).unwrap();
+        u.add("cb", Some(2)).unwrap();
+        assert_eq!(u.minimization_estimate(), (5, 5));
+        assert_eq!(TNode::<u8>::Empty.minimization_estimate(), (0, 0));
+    }
+
+    #[test]
+    fn keys_page() {
+        let mut t = TNode::new();
+        let keys = ["", "a", "ab", "abc", "b", "ba", "bb", "c"];
+        for k in keys {
+            t.add(k, Some(1)).unwrap();
+        }
+        let first = t.keys_page(None, 5);
+        assert_eq!(first, vec!["", "a", "ab", "abc", "b"]);
+        let second = t.keys_page(first.last().map(|k| k.as_str()), 5);
+        assert_eq!(second, vec!["ba", "bb", "c"]);
+        assert_eq!([first, second].concat(), keys);
+
+        assert_eq!(t.keys_page(Some("aa"), 2), vec!["ab", "abc"]);
+        assert_eq!(t.keys_page(Some("bz"), 2), vec!["c"]);
+        assert!(t.keys_page(Some("c"), 2).is_empty());
+        assert!(t.keys_page(None, 0).is_empty());
+    }
+
+    #[test]
+    fn metadata() {
+        let mut t: TNode<i32, (bool, u32)> = TNode::Empty;
+        t.add("ab", Some(1)).unwrap();
+        t.add("abc", Some(2)).unwrap();
+        t.add("x", Some(3)).unwrap();
+        assert_eq!(t.find("ab", true).unwrap().metadata(), &(false, 0));
+
+        assert!(t.set_metadata("ab", (true, 7)));
+        assert!(t.set_metadata("a", (true, 1)));
+        assert!(!t.set_metadata("ax", (true, 1)));
+        assert_eq!(t.find("ab", true).unwrap().metadata(), &(true, 7));
+        assert_eq!(t.find("a", false).unwrap().metadata(), &(true, 1));
+        assert_eq!(t.find("x", true).unwrap().metadata(), &(false, 0));
+
+        t.add("a", Some(4)).unwrap();
+        t.remove("abc", false);
+        assert_eq!(t.find("a", true).unwrap().metadata(), &(true, 1));
+        assert_eq!(t.find("ab", true).unwrap().metadata(), &(true, 7));
+    }
+
+    #[test]
+    fn is_prefix_free() {
+        let mut t = TNode::new();
+        for k in ["0", "10", "110", "111"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert!(t.is_prefix_free());
+
+        t.add("11", Some(1)).unwrap();
+        assert!(!t.is_prefix_free());
+        assert!(TNode::<u8>::new().is_prefix_free());
+    }
+
+    #[test]
+    fn keys_by_length() {
+        let mut t = TNode::new();
+        for k in ["a", "to", "be", "tea", "ten", "é", "inn"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let groups = t.keys_by_length();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[&1], vec!["a", "é"]);
+        assert_eq!(groups[&2], vec!["be", "to"]);
+        assert_eq!(groups[&3], vec!["inn", "tea", "ten"]);
+    }
+
+    #[test]
+    fn matches_mask() {
+        let mut t = TNode::new();
+        for k in [
+            "2023-01-A",
+            "2023-02-B",
+            "2024-01-A",
+            "2023-01-C",
+            "2023-1-A",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(
+            t.matches_mask("2023-01-A", &[6, 8]),
+            vec!["2023-01-A", "2023-01-C", "2023-02-B"]
+        );
+        assert_eq!(
+            t.matches_mask("2020-01-A", &[3, 6]),
+            vec!["2023-01-A", "2024-01-A"]
+        );
+        assert!(t.matches_mask("2025-01-A", &[8]).is_empty());
+        assert_eq!(t.matches_mask("2023-1-A", &[]), vec!["2023-1-A"]);
+    }
+
+    #[test]
+    fn count_range() {
+        let mut t = TNode::new();
+        let keys = ["", "a", "ab", "abc", "abd", "b", "ba", "bcd", "c", "ca"];
+        for k in keys {
+            t.add(k, Some(1)).unwrap();
+        }
+        let ranges = [
+            ("", "zzz"),
+            ("a", "b"),
+            ("ab", "abd"),
+            ("abb", "bc"),
+            ("b", "b"),
+            ("bz", "ca"),
+            ("", "a"),
+            ("c", "d"),
+            ("x", "y"),
+        ];
+        for (start, end) in ranges {
+            let expected = keys.iter().filter(|k| **k >= start && **k < end).count();
+            assert_eq!(t.count_range(start, end), expected, "[{}, {})", start, end);
+        }
+    }
+
+    #[test]
+    fn best_prefix_match() {
+        let mut t = TNode::new();
+        t.add("10.0.1.0", Some(1)).unwrap();
+        t.add("10.0.2.0", Some(2)).unwrap();
+        t.add("10.1.0.0", Some(3)).unwrap();
+        t.add("192.168.0.0", Some(4)).unwrap();
+
+        assert_eq!(
+            t.best_prefix_match("10.0.2.5"),
+            Some(("10.0.2.0".to_owned(), 7))
+        );
+        // "10.0.1.0" and "10.0.2.0" both share "10.0." with the query
+        assert_eq!(
+            t.best_prefix_match("10.0.9.9"),
+            Some(("10.0.1.0".to_owned(), 5))
+        );
+        assert_eq!(
+            t.best_prefix_match("10.1.0.0"),
+            Some(("10.1.0.0".to_owned(), 8))
+        );
+        assert_eq!(t.best_prefix_match("8.8.8.8"), None);
+        assert_eq!(t.best_prefix_match(""), None);
+    }
+
+    #[test]
+    fn compact_text() {
+        let mut t = TNode::new();
+        for k in [
+            "apple", "apply", "", "banana", "band", "bandana", "été", "étés",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let text = t.to_compact_text();
+        assert_eq!(text, "0:\n0:apple\n4:y\n0:banana\n3:d\n4:ana\n0:été\n3:s\n");
+        let back = TNode::from_compact_text(&text).unwrap();
+        assert_eq!(back.to_compact_text(), text);
+        assert!(back.contains_key("bandana"));
+        assert!(!back.contains_key("ban"));
+
+        assert_eq!(TNode::from_compact_text("0:a\n5:b\n").unwrap_err().line, 2);
+        assert_eq!(TNode::from_compact_text("x\n").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn keys_with_suffix() {
+        let mut t = TNode::new();
+        for k in [
+            "", "ing", "sing", "singing", "ring", "rang", "king", "né", "café", "é",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let naive = |t: &TNode<i32>, suffix: &str| {
+            let mut keys: Vec<String> = t.entries().into_iter().map(|(k, _)| k).collect();
+            keys.retain(|k| k.ends_with(suffix));
+            keys
+        };
+        let suffixes = ["", "g", "ing", "ging", "ang", "é", "fé", "x", "singing!"];
+        for s in suffixes {
+            assert_eq!(t.keys_with_suffix(s), naive(&t, s));
+        }
+        t.build_suffix_index();
+        for s in suffixes {
+            assert_eq!(t.keys_with_suffix(s), naive(&t, s));
+        }
+        assert_eq!(
+            t.keys_with_suffix("ing"),
+            ["ing", "king", "ring", "sing", "singing"]
+        );
+
+        // a stale index falls back to scanning
+        t.add("wing", Some(1)).unwrap();
+        assert_eq!(t.keys_with_suffix("wing"), ["wing"]);
+        t.remove("ring", false);
+        assert_eq!(t.keys_with_suffix("ring"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn weighted_avg_key_len() {
+        let mut t = TNode::new();
+        assert_eq!(t.weighted_avg_key_len(), 0.0);
+        t.add("a", Some(3u32)).unwrap();
+        t.add("abcd", Some(1)).unwrap();
+        t.add("abcdefgh", None).unwrap();
+        t.add("né", Some(4)).unwrap();
+        // (1 * 3 + 4 * 1 + 2 * 4) / 8
+        assert_eq!(t.weighted_avg_key_len(), 15.0 / 8.0);
+    }
+
+    #[test]
+    fn merge_sorted() {
+        let (one, two) = (Some(1), Some(2));
+        let old = ["b", "bandana", "car", "zebra"];
+        let batch = [
+            "", "a", "ab", "abc", "b", "band", "bandanas", "cart", "né", "nés",
+        ];
+
+        let mut merged = TNode::new();
+        let mut naive = TNode::new();
+        for k in old {
+            merged.add(k, one).unwrap();
+            naive.add(k, one).unwrap();
+        }
+        merged.merge_sorted(batch.iter().map(|k| (k.to_string(), two)));
+        for k in batch {
+            let _ = naive.add(k, two);
+        }
+        assert_eq!(merged, naive);
+        assert_eq!(merged.find("b", true).unwrap().content(), &Some(1));
+        assert_eq!(merged.snapshot_version(), 13);
+        assert_eq!(merged.keys_since(4).len(), 9);
+
+        // unsorted input still ends up in the trie
+        let mut unsorted = TNode::new();
+        unsorted.merge_sorted(batch.iter().rev().map(|k| (k.to_string(), two)));
+        let mut expected = TNode::new();
+        expected.merge_sorted(batch.iter().map(|k| (k.to_string(), two)));
+        assert_eq!(unsorted, expected);
+        assert_ne!(unsorted, naive);
+    }
+
+    #[test]
+    fn max_fanout() {
+        let mut t = TNode::new();
+        assert_eq!(t.max_fanout(), 0);
+        t.add("", Some(1)).unwrap();
+        assert_eq!(t.max_fanout(), 0);
+        for k in ["ab", "cd", "xa", "xb", "xc", "xd"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.max_fanout(), 4);
+    }
+
+    #[test]
+    fn completions_bounded() {
+        let mut t = TNode::new();
+        for k in [
+            "ca", "car", "card", "cards", "cardigan", "cat", "dog", "çar",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(
+            t.completions_bounded("ca", 3, 5),
+            ["car", "card", "cards", "cat"]
+        );
+        assert_eq!(t.completions_bounded("car", 0, 3), ["car"]);
+        assert_eq!(
+            t.completions_bounded("", 3, 3),
+            ["car", "cat", "dog", "çar"]
+        );
+        assert!(t.completions_bounded("ca", 6, 7).is_empty());
+        assert!(t.completions_bounded("x", 0, 10).is_empty());
+    }
+
+    #[test]
+    fn walk() {
+        let mut t = TNode::new();
+        assert_eq!(t.walk().count(), 0);
+        for k in ["ab", "abc", "ad", "b"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let walked: Vec<(String, bool, usize)> = t.walk().collect();
+        let expected = [
+            ("", false, 2),
+            ("a", false, 2),
+            ("ab", true, 1),
+            ("abc", true, 0),
+            ("ad", true, 0),
+            ("b", true, 0),
+        ];
+        assert_eq!(walked.len(), expected.len());
+        for (w, e) in walked.iter().zip(expected) {
+            assert_eq!((w.0.as_str(), w.1, w.2), e);
+        }
+    }
+
+    #[test]
+    fn jaccard() {
+        let build = |keys: &[&str]| {
+            let mut t = TNode::new();
+            for k in keys {
+                t.add(k, Some(1)).unwrap();
+            }
+            t
+        };
+        let a = build(&["", "car", "cart", "dog"]);
+        assert_eq!(a.jaccard(&build(&["dog", "cart", "car", ""])), 1.0);
+        assert_eq!(a.jaccard(&build(&["ca", "carts", "do"])), 0.0);
+        assert_eq!(a.jaccard(&TNode::new()), 0.0);
+        assert_eq!(TNode::<i32>::new().jaccard(&TNode::new()), 1.0);
+        // {car, dog} shared out of {"", car, cart, dog, cat, doge}
+        let b = build(&["car", "cat", "dog", "doge"]);
+        assert_eq!(a.jaccard(&b), 2.0 / 6.0);
+        assert_eq!(b.jaccard(&a), 2.0 / 6.0);
+    }
+
+    #[test]
+    fn remove_glob() {
+        let mut t = TNode::new();
+        for k in [
+            "", "tmp", "tmp/", "tmp/a", "tmp/a/b", "tmp/bc", "tmpx", "src/a", "src/ab",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(
+            t.remove_glob("tmp/*"),
+            ["tmp/", "tmp/a", "tmp/a/b", "tmp/bc"]
+        );
+        assert_eq!(t.remove_glob("src/?"), ["src/a"]);
+        assert!(t.remove_glob("nothing*").is_empty());
+        assert_eq!(t.leaf_keys(), ["src/ab", "tmpx"]);
+        assert!(t.find("tmp/", false).is_none());
+        assert_eq!(t.remove_glob("**"), ["", "src/ab", "tmp", "tmpx"]);
+        assert_eq!(t.count_terminals(), 0);
+    }
+
+    #[test]
+    fn longest_prefix_trace() {
+        let mut t = TNode::new();
+        assert!(t.longest_prefix_trace("abc").is_empty());
+        t.add("a", Some(1)).unwrap();
+        t.add("abé", None).unwrap();
+        t.add("abéd", Some(3)).unwrap();
+        assert_eq!(
+            t.longest_prefix_trace("abéx"),
+            [
+                ("".to_string(), false, None),
+                ("a".to_string(), true, Some(&1)),
+                ("ab".to_string(), false, None),
+                ("abé".to_string(), true, None),
+            ]
+        );
+        assert_eq!(t.longest_prefix_trace("").len(), 1);
+    }
+
+    #[test]
+    fn from_csv() {
+        let csv = "apple,red\napricot,orange,ish\nbanana\n,empty\ncherry,\n";
+        let t = TNode::from_csv(csv.as_bytes(), ',').unwrap();
+        assert_eq!(
+            t.entries(),
+            [
+                ("".to_string(), &Some("empty".to_string())),
+                ("apple".to_string(), &Some("red".to_string())),
+                ("apricot".to_string(), &Some("orange,ish".to_string())),
+                ("banana".to_string(), &None),
+                ("cherry".to_string(), &Some("".to_string())),
+            ]
+        );
+
+        let err = TNode::from_csv("a;1\nb;2\na;3\n".as_bytes(), ';').unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sample_keys() {
+        let mut t = TNode::new();
+        let keys: Vec<String> = (0..100).map(|i| format!("k{:03}", i)).collect();
+        for k in &keys {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.sample_keys(4), ["k000", "k025", "k050", "k075"]);
+        assert_eq!(t.sample_keys(3), ["k000", "k033", "k066"]);
+        assert!(t.sample_keys(0).is_empty());
+        assert_eq!(t.sample_keys(1000), keys);
+    }
+
+    #[test]
+    fn non_ascii_keys() {
+        let mut t = TNode::new();
+        for k in [
+            "", "cafe", "café", "naïve", "naive", "日本", "x\u{7f}", "zoë",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.non_ascii_keys(), ["café", "naïve", "zoë", "日本"]);
+    }
+
+    #[test]
+    fn map_keys() {
+        let mut t = TNode::new();
+        t.add(" b", Some(1)).unwrap();
+        t.add("a ", Some(2)).unwrap();
+        t.add("a", Some(3)).unwrap();
+        let t = t.map_keys(|k| k.trim().to_uppercase());
+        assert_eq!(
+            t.entries(),
+            [("A".to_string(), &Some(3)), ("B".to_string(), &Some(1))]
+        );
+    }
+
+    #[test]
+    fn keys_packed() {
+        let mut t = TNode::new();
+        assert!(t.keys_packed().is_empty());
+        for k in ["b", "", "ab", "été"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let packed = t.keys_packed();
+        assert_eq!(packed.len(), 12);
+        let text = String::from_utf8(packed).unwrap();
+        let keys: Vec<&str> = text.split_terminator('\0').collect();
+        assert_eq!(keys, ["", "ab", "b", "été"]);
+    }
+
+    #[test]
+    fn deepest_common_node() {
+        let mut t = TNode::new();
+        assert_eq!(t.deepest_common_node(&["a"]), None);
+        for k in ["interview", "internal", "internet", "into", "inté"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.deepest_common_node(&[]), None);
+        let deep = ["internals", "interns", "internet"];
+        assert_eq!(t.deepest_common_node(&deep).unwrap(), "intern");
+        assert_eq!(
+            t.deepest_common_node(&[deep[0], deep[1], deep[2], "int"])
+                .unwrap(),
+            "int"
+        );
+        assert_eq!(t.deepest_common_node(&["intéx", "intéy"]).unwrap(), "inté");
+        assert_eq!(t.deepest_common_node(&["intex", "intey"]).unwrap(), "inte");
+        assert_eq!(t.deepest_common_node(&["x", "in"]).unwrap(), "");
+    }
+
+    #[test]
+    fn all_values() {
+        let mut t = TNode::new();
+        assert!(t.all_values(|_: &i32| false));
+        for (k, v) in [("a", Some(1)), ("ab", Some(5)), ("b", Some(2))] {
+            t.add(k, v).unwrap();
+        }
+        assert!(t.all_values(|v| *v > 0));
+
+        let mut checked = 0;
+        assert!(!t.all_values(|v| {
+            checked += 1;
+            *v < 5
+        }));
+        assert_eq!(checked, 2);
+
+        t.add("c", None).unwrap();
+        assert!(!t.all_values(|v| *v > 0));
+    }
+
+    #[test]
+    fn keys_interned() {
+        let mut t = TNode::new();
+        for k in ["b", "", "ab", "été", "a"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let (buf, ranges) = t.keys_interned();
+        assert_eq!(buf, "aabbété");
+        let keys: Vec<&str> = ranges.into_iter().map(|r| &buf[r]).collect();
+        let sorted: Vec<String> = t.entries().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, sorted);
+    }
+
+    #[test]
+    fn depth_profile() {
+        let mut t = TNode::new();
+        assert!(t.depth_profile().is_empty());
+        t.add("", Some(1)).unwrap();
+        assert_eq!(t.depth_profile(), [1]);
+        for k in ["ab", "ac", "ad", "b", "bcde"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.depth_profile(), [1, 2, 4, 1, 1]);
+    }
+
+    #[test]
+    fn find_dead_branches() {
+        let mut t = TNode::new();
+        assert!(t.find_dead_branches().is_empty());
+        for k in ["a", "abcd", "abce", "b", "bx"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert!(t.find_dead_branches().is_empty());
+        t.node_mut("abcd").unwrap().clear_terminal();
+        t.node_mut("abce").unwrap().clear_terminal();
+        t.node_mut("bx").unwrap().clear_terminal();
+        assert_eq!(t.find_dead_branches(), ["ab", "bx"]);
+        t.node_mut("a").unwrap().clear_terminal();
+        t.node_mut("b").unwrap().clear_terminal();
+        assert_eq!(t.find_dead_branches(), ["a", "b"]);
+
+        let mut t = TNode::new();
+        for k in ["a", "ab", "b"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        for k in ["a", "ab", "b"] {
+            assert!(t.remove(k, false));
+        }
+        assert!(t.find_dead_branches().is_empty());
+    }
+
+    #[test]
+    fn aggregate_prefix() {
+        let mut t = TNode::new();
+        let hits = [
+            ("/", 1),
+            ("/api", 10),
+            ("/api/users", 5),
+            ("/apis", 2),
+            ("/home", 7),
+        ];
+        for (k, n) in hits {
+            t.add(k, Some(n)).unwrap();
+        }
+        t.add("/api/none", None).unwrap();
+        assert_eq!(t.aggregate_prefix("/api", 0, |sum, n| sum + n), 17);
+        assert_eq!(t.aggregate_prefix("/api/", 0, |sum, n| sum + n), 5);
+        assert_eq!(t.aggregate_prefix("", 0, |sum, n| sum + n), 25);
+        assert_eq!(t.aggregate_prefix("/x", 42, |sum, n| sum + n), 42);
+        let values = t.aggregate_prefix("/api", vec![], |mut v, n| {
+            v.push(*n);
+            v
+        });
+        assert_eq!(values, [10, 5, 2]);
     }
 
     #[test]
-    fn add_to_empty_trie() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        match t {
-            TNode::Node(node) => {
-                assert_eq!(node.content, &None);
-                assert_eq!(node.is_terminal, false);
-                let subt = node.children.get(&'a').unwrap();
-                assert_eq!(subt.content(), &Some(1));
-                assert_eq!(subt.is_terminal(), true);
+    fn anagrams() {
+        let mut t = TNode::new();
+        for k in [
+            "listen", "silent", "enlist", "tinsel", "list", "listens", "lisent", "silence",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(
+            t.anagrams("inlets"),
+            ["enlist", "lisent", "listen", "silent", "tinsel"]
+        );
+        assert!(t.has_anagram("stil"));
+        assert!(!t.has_anagram("listt"));
+        assert!(!t.has_anagram(""));
+        t.add("", Some(1)).unwrap();
+        assert_eq!(t.anagrams(""), [""]);
+    }
+
+    #[test]
+    fn rank() {
+        let mut t = TNode::new();
+        assert_eq!(t.rank("a"), 0);
+        for k in ["", "b", "ba", "bb", "c", "cat", "d"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.rank(""), 0);
+        assert_eq!(t.rank("a"), 1);
+        assert_eq!(t.rank("b"), 1);
+        assert_eq!(t.rank("bab"), 3);
+        assert_eq!(t.rank("c"), 4);
+        assert_eq!(t.rank("d"), 6);
+        assert_eq!(t.rank("zzz"), 7);
+    }
+
+    #[test]
+    fn select() {
+        let mut t = TNode::new();
+        assert_eq!(t.select(0), None);
+        let keys = ["", "b", "ba", "bb", "c", "cat", "d", "été"];
+        for k in keys {
+            t.add(k, Some(1)).unwrap();
+        }
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.select(i).unwrap(), *k);
+            assert_eq!(t.select(t.rank(k)).unwrap(), *k);
+        }
+        assert_eq!(t.select(keys.len()), None);
+    }
+
+    #[test]
+    fn symmetric_difference() {
+        let build = |keys: &[&str]| {
+            let mut t = TNode::new();
+            for k in keys {
+                t.add(k, Some(1)).unwrap();
             }
-            _ => panic!("t should be TNode::Node"),
+            t
+        };
+        let a = build(&["", "car", "cart", "dog"]);
+        let none: Vec<String> = vec![];
+        assert_eq!(
+            a.symmetric_difference(&build(&["dog", "cart", "car", ""])),
+            (none.clone(), none.clone())
+        );
+        assert_eq!(
+            a.symmetric_difference(&build(&["ca", "carts", "do"])),
+            (
+                vec![
+                    "".to_string(),
+                    "car".to_string(),
+                    "cart".to_string(),
+                    "dog".to_string()
+                ],
+                vec!["ca".to_string(), "carts".to_string(), "do".to_string()]
+            )
+        );
+        let (mine, theirs) = a.symmetric_difference(&build(&["car", "cat", "dog", "doge", "x"]));
+        assert_eq!(mine, ["", "cart"]);
+        assert_eq!(theirs, ["cat", "doge", "x"]);
+        let (mine, theirs) = TNode::new().symmetric_difference(&a);
+        assert_eq!((mine, theirs.len()), (none, 4));
+    }
+
+    #[test]
+    fn fits_within() {
+        let mut t = TNode::new();
+        assert!(t.fits_within(0));
+        for k in ["", "a", "ab", "abc", "b", "c"] {
+            t.add(k, Some(1)).unwrap();
         }
+        assert!(t.fits_within(6));
+        assert!(t.fits_within(100));
+        assert!(!t.fits_within(5));
+        assert!(!t.fits_within(0));
+        assert!(t.remove("abc", false));
+        assert!(t.fits_within(5));
     }
 
     #[test]
-    fn add_single_char_string() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        t.add("ab", &Some(1)).unwrap();
-        t.add("c", &Some(1)).unwrap();
-        t.add("d", &Some(1)).unwrap();
-        assert_eq!(t.pp(false), "a\n b\nc\nd\n")
+    fn longest_terminal_prefix() {
+        let mut t = TNode::new();
+        for k in ["ab", "abcd"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.longest_terminal_prefix("abcx").unwrap(), "ab");
+        assert_eq!(t.longest_prefix_opts("abcx", PrefOptions::default()), "abc");
+        assert_eq!(t.longest_terminal_prefix("abcde").unwrap(), "abcd");
+        assert_eq!(t.longest_terminal_prefix("a"), None);
+        let terminal = PrefOptions {
+            must_be_terminal: true,
+            ..Default::default()
+        };
+        assert_eq!(t.longest_prefix_opts("x", terminal), "");
+        assert_eq!(t.longest_terminal_prefix("x"), None);
+        t.add("", Some(1)).unwrap();
+        assert_eq!(t.longest_terminal_prefix("x").unwrap(), "");
     }
 
     #[test]
-    fn contains_key() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        assert!(t.contains_key("a"));
+    fn serialize_keys_only() {
+        let mut t = TNode::new();
+        let mut buf = vec![];
+        t.serialize_keys_only(&mut buf).unwrap();
+        assert_eq!(buf, [0, 0, 0, 0]);
+        assert!(matches!(
+            TNode::deserialize_keys_only(&mut buf.as_slice()).unwrap(),
+            TNode::Empty
+        ));
 
-        t.add("abc", &Some(2)).unwrap();
-        assert!(!t.contains_key("b"));
-        assert!(t.contains_key("abc"));
+        let keys = ["", "a", "ab", "abc", "b", "bé", "bx", "zzz"];
+        for k in keys {
+            t.add(k, Some(1)).unwrap();
+        }
+        buf.clear();
+        t.serialize_keys_only(&mut buf).unwrap();
+        // 10 nodes, 19 LOUDS bits, 10 terminal bits, 9 labels of which one is 2 bytes
+        assert_eq!(buf.len(), 4 + 3 + 2 + 10);
+        let back = TNode::deserialize_keys_only(&mut buf.as_slice()).unwrap();
+        for k in [
+            "", "a", "ab", "abc", "abcd", "b", "bé", "bx", "by", "z", "zz", "zzz",
+        ] {
+            assert_eq!(back.contains_key(k), t.contains_key(k), "{}", k);
+        }
+        assert_eq!(back.count_terminals(), keys.len());
+
+        buf.pop();
+        assert!(TNode::deserialize_keys_only(&mut buf.as_slice()).is_err());
+        assert!(TNode::deserialize_keys_only(&mut [3, 0, 0, 0, 0, 0xff, 0].as_slice()).is_err());
     }
 
     #[test]
-    fn show_content() {
-        let mut t = TNode::Empty;
-        assert_eq!(t.pp(true), "[empty]\n");
+    fn near_duplicate_pairs() {
+        let mut t = TNode::new();
+        for k in [
+            "", "a", "color", "colour", "colr", "kitten", "sitten", "sitting", "zebra",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let pair = |a: &str, b: &str| (a.to_string(), b.to_string());
+        assert_eq!(
+            t.near_duplicate_pairs(),
+            [
+                pair("", "a"),
+                pair("color", "colour"),
+                pair("color", "colr"),
+                pair("kitten", "sitten"),
+            ]
+        );
+    }
 
-        t.add("a", &Some(1)).unwrap();
-        assert_eq!(t.pp(true), "a  (1)\n");
+    #[test]
+    fn get_or() {
+        let mut t = TNode::new();
+        t.add("a", Some(1)).unwrap();
+        t.add("ab", None).unwrap();
+        assert_eq!(*t.get_or("a", &0), 1);
+        assert_eq!(*t.get_or("ab", &0), 0);
+        assert_eq!(*t.get_or("abc", &0), 0);
+        assert_eq!(*t.get_or("", &-1), -1);
+    }
 
-        t.add("abc", &Some(2)).unwrap();
-        assert_eq!(t.pp(true), "a\n bc  (2)\n");
+    #[test]
+    fn bfs() {
+        let mut t = TNode::new();
+        assert_eq!(t.bfs().count(), 0);
+        for k in ["ab", "abc", "b", "ba"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let bfs: Vec<String> = t.bfs().map(|(k, _)| k).collect();
+        assert_eq!(bfs, ["", "a", "b", "ab", "ba", "abc"]);
+        let dfs: Vec<String> = t.walk().map(|(k, _, _)| k).collect();
+        assert_eq!(dfs, ["", "a", "ab", "abc", "b", "ba"]);
+        let terminals: Vec<bool> = t.bfs().map(|(_, term)| term).collect();
+        assert_eq!(terminals, [false, false, true, true, true, true]);
+    }
 
-        t.add("d", &Some(3)).unwrap();
-        assert_eq!(t.pp(true), "a\n bc  (2)\nd  (3)\n");
+    #[test]
+    fn run_length_values() {
+        let (a, b) = (Some('a'), Some('b'));
+        let mut t = TNode::new();
+        for (k, v) in [
+            ("k1", a),
+            ("k2", a),
+            ("k3", a),
+            ("k4", b),
+            ("k5", None),
+            ("k6", b),
+            ("k7", a),
+        ] {
+            t.add(k, v).unwrap();
+        }
+        let run = |first: &str, last: &str, v| (first.to_string(), last.to_string(), v);
+        assert_eq!(
+            t.run_length_values(),
+            [
+                run("k1", "k3", 'a'),
+                run("k4", "k4", 'b'),
+                run("k6", "k6", 'b'),
+                run("k7", "k7", 'a'),
+            ]
+        );
+    }
 
-        t.add("e", &Some(4)).unwrap();
-        assert_eq!(t.pp(true), "a\n bc  (2)\nd  (3)\ne  (4)\n");
+    #[test]
+    fn into_sorted_vec() {
+        let contents = [Some(3), None, Some(1), Some(2)];
+        let mut t = TNode::new();
+        for (k, c) in ["b", "", "ab", "a"].into_iter().zip(contents) {
+            t.add(k, c).unwrap();
+        }
+        let v = t.into_sorted_vec();
+        assert_eq!(v.capacity(), 4);
+        assert_eq!(
+            v,
+            [
+                ("".to_string(), None),
+                ("a".to_string(), Some(2)),
+                ("ab".to_string(), Some(1)),
+                ("b".to_string(), Some(3)),
+            ]
+        );
+        assert!(TNode::<i32>::new().into_sorted_vec().is_empty());
     }
 
     #[test]
-    fn longest_prefix() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(1)).unwrap();
-        t.add("this is more words", &Some(1)).unwrap();
-        let res = t.longest_prefix("this is more wo", false);
-        let expected: Vec<char> = "this is more wo".chars().collect();
-        assert_eq!(res.chars().collect::<Vec<_>>(), expected);
+    fn min_value_in_prefix() {
+        let mut t = TNode::new();
+        let counts = [
+            ("/a", 5),
+            ("/api/x", 3),
+            ("/api/y", 9),
+            ("/api/z", 3),
+            ("/b", 1),
+        ];
+        for (k, n) in counts {
+            t.add(k, Some(n)).unwrap();
+        }
+        t.add("/api/none", None).unwrap();
+        assert_eq!(
+            t.min_value_in_prefix("/api"),
+            Some(("/api/x".to_string(), &3))
+        );
+        assert_eq!(t.min_value_in_prefix("/"), Some(("/b".to_string(), &1)));
+        assert_eq!(t.min_value_in_prefix("/api/none"), None);
+        assert_eq!(t.min_value_in_prefix("/c"), None);
     }
 
     #[test]
-    fn longest_prefix_no_full_match() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(1)).unwrap();
-        t.add("this is more words", &Some(1)).unwrap();
-        let res = t.longest_prefix("this is weeks", false);
-        let expected: Vec<char> = "this is w".chars().collect();
-        assert_eq!(res.chars().collect::<Vec<_>>(), expected);
+    fn diff() {
+        let (one, two) = (Some(1), Some(2));
+        let mut a = TNode::new();
+        let mut b = TNode::new();
+        for k in ["", "car", "cart", "dog"] {
+            a.add(k, one).unwrap();
+        }
+        for (k, c) in [("", one), ("car", two), ("cat", one), ("dog", one)] {
+            b.add(k, c).unwrap();
+        }
+        assert_eq!(
+            a.diff(&b),
+            [
+                "\"car\" has different contents",
+                "\"cart\" only in self",
+                "\"cat\" only in other",
+            ]
+        );
+        assert!(a.diff(&a).is_empty());
+
+        let mut many = TNode::new();
+        for i in 0..15 {
+            many.add(&format!("k{:02}", i), one).unwrap();
+        }
+        let d = many.diff(&TNode::new());
+        assert_eq!(d.len(), 11);
+        assert_eq!(d[10], "... and 5 more");
     }
 
     #[test]
-    fn longest_prefix_terminal() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(1)).unwrap();
-        t.add("this is more words", &Some(1)).unwrap();
-        let res = t.longest_prefix("this is more wo", true);
-        let expected: Vec<char> = "this is more".chars().collect();
-        assert_eq!(res.chars().collect::<Vec<_>>(), expected);
+    fn delta_against_sorted() {
+        let mut t = TNode::new();
+        for k in ["", "car", "cart", "dog"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let (added, removed) = t.delta_against_sorted(&["car", "cat", "cat", "dog", "é"]);
+        assert_eq!(added, ["cat", "é"]);
+        assert_eq!(removed, ["", "cart"]);
+        assert_eq!(
+            t.delta_against_sorted(&["", "car", "cart", "dog"]),
+            (vec![], vec![])
+        );
+        let (added, removed) = TNode::<i32>::new().delta_against_sorted(&["a"]);
+        assert_eq!((added, removed.len()), (vec!["a".to_string()], 0));
     }
 
     #[test]
-    fn longest_prefix_fail() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(1)).unwrap();
-        t.add("this is more words", &Some(1)).unwrap();
-        let res = t.longest_prefix("this is", true);
-        assert!(res.is_empty());
+    fn add_prefix() {
+        let mut t = TNode::new();
+        for (k, v) in [("", 0), ("a", 1), ("ab", 2), ("b", 3)] {
+            t.add(k, Some(v)).unwrap();
+        }
+        let owned = |t: &TNode<i32>| -> Vec<(String, Option<i32>)> {
+            t.entries().into_iter().map(|(k, v)| (k, *v)).collect()
+        };
+        let expected: Vec<_> = owned(&t)
+            .into_iter()
+            .map(|(k, v)| (format!("né/{}", k), v))
+            .collect();
+        let version = t.snapshot_version();
+        t.add_prefix("né/");
+        assert_eq!(owned(&t), expected);
+        assert_eq!(t.get("né/"), Some(&0));
+        assert!(!t.contains_key(""));
+        assert!(t.find("né", false).is_some());
+        assert!(t.snapshot_version() > version);
+
+        t.add_prefix("");
+        assert_eq!(owned(&t), expected);
+        let mut empty = TNode::<i32>::new();
+        empty.add_prefix("x");
+        assert!(empty.entries().is_empty());
     }
 
     #[test]
-    fn find() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(2)).unwrap();
-        t.add("this is even more", &Some(3)).unwrap();
-        let res = t.find("this is more", false).unwrap();
-        //let expected: Vec<char> = "this is more".chars().collect();
-        assert_eq!(res.content().unwrap(), 2)
+    fn pin_subtree() {
+        let contents = [Some(1), Some(2), None, Some(4), Some(5)];
+        let keys = ["/api", "/api/users", "/api/users/me", "/api/v2", "/home"];
+        let mut t = TNode::new();
+        for (k, c) in keys.iter().zip(contents) {
+            t.add(k, c).unwrap();
+        }
+        assert!(t.pin_subtree("/x").is_none());
+        let api = t.pin_subtree("/api").unwrap();
+        for k in keys.iter().chain(&["/ap", "/api/u", "/apix"]) {
+            let direct = t.find(k, true).and_then(|n| n.content().as_ref());
+            let expected = if k.starts_with("/api") { direct } else { None };
+            assert_eq!(api.get(k), expected, "{}", k);
+        }
+        for p in ["/api", "/api/", "/api/users", "/api/v3"] {
+            assert_eq!(api.complete(p), t.completions_bounded(p, 0, usize::MAX));
+        }
+        assert!(api.complete("/").is_empty());
     }
+
     #[test]
-    fn find_terminal() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(2)).unwrap();
-        t.add("this is even more", &Some(3)).unwrap();
-        let res = t.find("this is more", true).unwrap();
-        //let expected: Vec<char> = "this is more".chars().collect();
-        assert_eq!(res.content().unwrap(), 2);
+    fn add_bounded() {
+        let one = Some(1);
+        let mut t = TNode::new();
+        assert_eq!(t.add_bounded("b", one, 2), None);
+        assert_eq!(t.add_bounded("d", one, 2), None);
+        assert_eq!(t.add_bounded("a", one, 2).unwrap(), "d");
+        assert_eq!(t.add_bounded("c", one, 2).unwrap(), "c");
+        assert_eq!(t.add_bounded("", one, 2).unwrap(), "b");
+        assert_eq!(t.add_bounded("a", one, 2), None);
+        let keys: Vec<String> = t.entries().into_iter().map(|(k, _)| k).collect();
+        assert_eq!(keys, ["", "a"]);
+        assert_eq!(t.add_bounded("x", one, 0).unwrap(), "x");
+        assert_eq!(t.add_bounded("x", one, 0).unwrap(), "x");
+        assert_eq!(t.count_terminals(), 2);
     }
+
+    #[cfg(feature = "regex")]
     #[test]
-    fn find_terminal_fail() {
-        let mut t = TNode::Empty;
-        t.add("this is words", &Some(1)).unwrap();
-        t.add("this is more", &Some(1)).unwrap();
-        t.add("this is even more", &Some(1)).unwrap();
-        let pref = t.find("this is more wo", true);
-        assert!(pref.is_none())
+    fn regex_search() {
+        let mut t = TNode::new();
+        let keys = ["", "ab", "abc", "abd", "b", "bb", "cat", "cät", "dog"];
+        for k in keys {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.regex_search("ab[cd]").unwrap(), ["abc", "abd"]);
+        assert_eq!(t.regex_search("b*").unwrap(), ["", "b", "bb"]);
+        assert_eq!(t.regex_search("c.t").unwrap(), ["cat", "cät"]);
+        assert_eq!(t.regex_search("ab|dog").unwrap(), ["ab", "dog"]);
+        assert!(t.regex_search("x+").unwrap().is_empty());
+        assert!(t.regex_search("(").is_err());
+        // word boundaries are not supported by the DFA and go through the fallback
+        assert_eq!(t.regex_search(r"\bab\w").unwrap(), ["abc", "abd"]);
+
+        // one alternative is a prefix of another, and lazy quantifiers
+        let mut t = TNode::new();
+        for k in ["a", "ab", "abc", "b"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.regex_search("a|ab").unwrap(), ["a", "ab"]);
+        assert_eq!(t.regex_search("a.*?").unwrap(), ["a", "ab", "abc"]);
+        assert_eq!(t.regex_search("(a|ab)c?").unwrap(), ["a", "ab", "abc"]);
     }
 
     #[test]
-    fn remove() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        t.add("abc", &Some(2)).unwrap();
-        t.add("abcd", &Some(3)).unwrap();
+    fn next_chars() {
+        let mut t = TNode::new();
+        for k in ["bat", "bet", "bot", "bé", "b"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.next_chars("b"), ['a', 'e', 'o', 'é']);
+        assert_eq!(t.next_chars(""), ['b']);
+        assert_eq!(t.next_chars("ba"), ['t']);
+        assert!(t.next_chars("bat").is_empty());
+        assert!(t.next_chars("x").is_empty());
+    }
+
+    #[test]
+    fn insert_shape() {
+        let mut t = TNode::new();
+        assert_eq!(t.insert_shape("ab"), InsertShape::NewBranch { at: 0 });
+        assert_eq!(t.insert_shape(""), InsertShape::NewBranch { at: 0 });
+        for k in ["abc", "abd", "x"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.insert_shape("abc"), InsertShape::AlreadyPresent);
+        assert_eq!(
+            t.insert_shape("abcde"),
+            InsertShape::ExtendsChain { shared: 3 }
+        );
+        assert_eq!(
+            t.insert_shape("xé"),
+            InsertShape::ExtendsChain { shared: 1 }
+        );
+        assert_eq!(t.insert_shape("abz"), InsertShape::NewBranch { at: 2 });
+        assert_eq!(t.insert_shape("q"), InsertShape::NewBranch { at: 0 });
+        assert_eq!(t.insert_shape("ab"), InsertShape::NewBranch { at: 2 });
+        assert_eq!(t.insert_shape(""), InsertShape::NewBranch { at: 0 });
+    }
+
+    #[test]
+    fn populated_entries() {
+        let mut t = TNode::new();
+        for (k, c) in [
+            ("", Some(0)),
+            ("a", None),
+            ("ab", Some(2)),
+            ("b", None),
+            ("c", Some(3)),
+        ] {
+            t.add(k, c).unwrap();
+        }
+        let populated: Vec<(String, &i32)> = t.populated_entries().collect();
+        assert_eq!(
+            populated,
+            [
+                ("".to_string(), &0),
+                ("ab".to_string(), &2),
+                ("c".to_string(), &3)
+            ]
+        );
+    }
+
+    #[test]
+    fn frequent_prefix() {
+        let mut t = TNode::new();
+        assert_eq!(t.frequent_prefix(1), None);
+        for k in [
+            "/api/v1/a",
+            "/api/v1/b",
+            "/api/v1/c",
+            "/api/v2/a",
+            "/home/x",
+            "/home/y",
+            "/z",
+        ] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.frequent_prefix(3).unwrap(), "/api/v1/");
+        assert_eq!(t.frequent_prefix(4).unwrap(), "/api/v");
+        assert_eq!(t.frequent_prefix(2).unwrap(), "/api/v1/");
+        assert_eq!(t.frequent_prefix(1).unwrap(), "/api/v1/a");
+        assert_eq!(t.frequent_prefix(7).unwrap(), "/");
+        assert_eq!(t.frequent_prefix(8), None);
+    }
+
+    #[test]
+    fn divergence_depth() {
+        let mut t = TNode::new();
+        assert_eq!(t.divergence_depth("ab", "ab"), 0);
+        for k in ["abcd", "abce", "xé"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.divergence_depth("abcd", "abcd"), 4);
+        assert_eq!(t.divergence_depth("abcdzz", "abcdzz"), 4);
+        assert_eq!(t.divergence_depth("abcd", "abce"), 3);
+        assert_eq!(t.divergence_depth("xé", "xéa"), 2);
+        assert_eq!(t.divergence_depth("abcd", "xé"), 0);
+        assert_eq!(t.divergence_depth("q", "q"), 0);
+    }
+
+    #[test]
+    fn key_set() {
+        struct NotClone;
+        let mut t = TNode::new();
+        for k in ["", "a", "abc", "b"] {
+            t.add(k, Some(NotClone)).unwrap();
+        }
+        let keys: TNode<()> = t.key_set();
+        for k in ["", "a", "ab", "abc", "abcd", "b", "c"] {
+            assert_eq!(keys.contains_key(k), t.contains_key(k), "{}", k);
+        }
+        assert_eq!(keys.find("abc", true).unwrap().content(), &Some(()));
+        assert_eq!(keys.snapshot_version(), t.snapshot_version());
+    }
 
+    #[test]
+    fn remove_prefix_key() {
+        let mut t = TNode::new();
+        assert!(!t.remove("a", false));
+        assert!(!t.remove("", false));
+        t.add("abc", Some(1)).unwrap();
+        t.add("abd", Some(2)).unwrap();
+        let (shape, version) = (t.pp(true), t.snapshot_version());
+        assert!(!t.remove("", false));
         assert!(!t.remove("ab", false));
-        assert!(t.contains_key("a"));
-        assert!(t.contains_key("abc"));
-        assert!(t.contains_key("abcd"));
+        assert!(!t.remove("a", false));
+        assert!(!t.remove("abcd", false));
+        assert_eq!(t.pp(true), shape);
+        assert_eq!(t.snapshot_version(), version);
 
-        assert!(t.remove("abc", true));
-        assert!(t.contains_key("a"));
-        assert!(!t.contains_key("abc"));
-        assert!(!t.contains_key("abcd"));
+        assert!(t.remove("abc", false));
+        assert_eq!(t.snapshot_version(), version + 1);
+        assert!(t.remove("ab", true));
+        assert!(!t.contains_key("abd"));
+    }
 
-        assert!(t.remove("a", false));
-        assert!(t.is_empty());
+    #[test]
+    fn remove_empty_key() {
+        let mut t = TNode::new();
+        t.add("", Some(1)).unwrap();
+        assert!(t.remove("", false));
+        assert!(!t.contains_key(""));
+        assert!(!t.remove("", false));
+        assert_eq!(t.snapshot_version(), 2);
+
+        t.add("", Some(1)).unwrap();
+        t.add("ab", Some(2)).unwrap();
+        assert!(t.remove("", false));
+        assert!(!t.contains_key(""));
+        assert!(t.contains_key("ab"));
+        assert!(t.remove("", true));
+        assert!(!t.contains_key("ab"));
+        assert_eq!(t.count_terminals(), 0);
     }
 
     #[test]
-    fn remove_non_terminal() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        t.add("abc", &Some(2)).unwrap();
-        t.remove("abc", false);
-        println!("{}", t.pp(true));
-        let expected = "a\n";
-        assert_eq!(t.pp(false), expected);
+    fn dominant_chars() {
+        let mut t = TNode::new();
+        assert!(t.dominant_chars().is_empty());
+        for k in ["a/x", "b/y", "c/z", "d-x", "ab"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.dominant_chars(), [('a', 1), ('/', 3), ('x', 2)]);
     }
+
     #[test]
-    fn remove_subtree() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        t.add("abc", &Some(2)).unwrap();
-        t.remove("ab", true);
-        println!("{}", t.pp(true));
-        let expected = "a\n";
-        assert_eq!(t.pp(false), expected);
+    fn get() {
+        let mut t = TNode::new();
+        assert_eq!(t.get(""), None);
+        assert_eq!(t.get("a"), None);
+        t.add("a", Some(1)).unwrap();
+        t.add("abc", Some(3)).unwrap();
+        t.add("abd", None).unwrap();
+        assert_eq!(t.get("a"), Some(&1));
+        assert_eq!(t.get("abc"), Some(&3));
+        assert_eq!(t.get("abd"), None);
+        assert_eq!(t.get("ab"), None);
+        assert_eq!(t.get(""), None);
+        assert_eq!(t.get("abcd"), None);
+        assert_eq!(t.get("x"), None);
     }
+
     #[test]
-    fn remove_non_existing() {
-        let mut t = TNode::Empty;
-        t.add("a", &Some(1)).unwrap();
-        t.add("abc", &Some(2)).unwrap();
-        let expected = t.pp(false);
-        t.remove("xyz", true);
-        println!("{}", t.pp(true));
-        assert_eq!(t.pp(false), expected);
+    fn get_mut() {
+        let mut t = TNode::new();
+        t.add("a", Some(vec![1])).unwrap();
+        t.add("abc", None).unwrap();
+        t.get_mut("a").unwrap().push(2);
+        assert_eq!(t.get("a"), Some(&vec![1, 2]));
+        assert!(t.get_mut("ab").is_none());
+        assert!(t.get_mut("abc").is_none());
+        assert!(t.get_mut("x").is_none());
+    }
+
+    #[test]
+    fn self_test() {
+        let mut t = TNode::new();
+        assert_eq!(t.self_test(&["", "a"]), Ok(()));
+        for k in ["", "a", "abc", "abd", "b", "été"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let queries = [
+            "", "a", "ab", "abc", "abcd", "abd", "b", "bz", "c", "é", "été", "étés",
+        ];
+        assert_eq!(t.self_test(&queries), Ok(()));
+        assert!(t.check_find_agrees("ab", true, false).is_ok());
+        assert!(t.check_find_agrees("abc", true, false).is_err());
+        assert!(t.check_find_agrees("abcd", false, true).is_err());
+    }
+
+    #[test]
+    fn maximal_keys() {
+        let mut t = TNode::new();
+        assert_eq!(t.maximal_keys().count(), 0);
+        for k in ["", "a", "ab", "abc", "abd", "b", "ba", "c"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let maximal: Vec<String> = t.maximal_keys().collect();
+        assert_eq!(maximal, ["abc", "abd", "ba", "c"]);
+        assert_eq!(maximal, t.leaf_keys());
+        assert_eq!(t.maximal_keys().next().unwrap(), "abc");
+    }
+
+    #[test]
+    fn keys() {
+        let mut t = TNode::new();
+        for k in ["a", "abc", "ab"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.keys().collect::<Vec<_>>(), ["a", "ab", "abc"]);
+        t.add("", None).unwrap();
+        t.add("b", None).unwrap();
+        assert_eq!(t.keys().collect::<Vec<_>>(), ["", "a", "ab", "abc", "b"]);
+        assert_eq!(TNode::<i32>::new().keys().count(), 0);
+    }
+
+    #[test]
+    fn would_exceed_nodes() {
+        let node_count = |t: &TNode<i32>| t.depth_profile().iter().sum::<usize>();
+        let batch = ["car", "cart", "cat", "dé", "", "dog"];
+        let mut empty = TNode::new();
+        let mut t = TNode::new();
+        t.add("ca", None).unwrap();
+        t.add("do", None).unwrap();
+        for (t, expected) in [(&mut empty, 10), (&mut t, 5)] {
+            assert!(!t.would_exceed_nodes(&batch, expected));
+            assert!(t.would_exceed_nodes(&batch, expected - 1));
+            let before = node_count(t);
+            for k in batch {
+                t.add(k, Some(1)).unwrap();
+            }
+            assert_eq!(node_count(t) - before, expected);
+            assert!(!t.would_exceed_nodes(&batch, 0));
+        }
+    }
+
+    #[test]
+    fn iter() {
+        let mut t = TNode::new();
+        for (k, v) in [("b", Some(2)), ("", Some(0)), ("ab", None), ("a", Some(1))] {
+            t.add(k, v).unwrap();
+        }
+        t.add("abcé", Some(4)).unwrap();
+        let entries: Vec<_> = t.iter().collect();
+        assert_eq!(
+            entries,
+            [
+                ("".to_string(), &0),
+                ("a".to_string(), &1),
+                ("abcé".to_string(), &4),
+                ("b".to_string(), &2),
+            ]
+        );
+        assert_eq!(TNode::<i32>::new().iter().count(), 0);
+    }
+
+    #[test]
+    fn values() {
+        let mut t = TNode::new();
+        for (k, v) in [("b", Some(2)), ("", Some(0)), ("ab", None), ("a", Some(1))] {
+            t.add(k, v).unwrap();
+        }
+        t.add("abc", Some(3)).unwrap();
+        assert_eq!(t.values().collect::<Vec<_>>(), [&0, &1, &3, &2]);
+        let from_iter: Vec<_> = t.iter().map(|(_, v)| v).collect();
+        assert_eq!(t.values().collect::<Vec<_>>(), from_iter);
+        assert_eq!(TNode::<i32>::new().values().count(), 0);
+    }
+
+    #[test]
+    fn key_at() {
+        let mut t = TNode::new();
+        for k in ["b", "", "abc", "a", "bé", "ab"] {
+            t.add(k, None::<i32>).unwrap();
+        }
+        let keys: Vec<String> = t.keys().collect();
+        assert_eq!(t.key_count(), keys.len());
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.key_at(i).as_ref(), Some(k));
+        }
+        assert_eq!(t.key_at(keys.len()), None);
+        assert_eq!(TNode::<i32>::new().key_count(), 0);
+    }
+
+    #[test]
+    fn nodes_under_prefix() {
+        let build = || {
+            let mut t = TNode::new();
+            for k in ["", "abcd", "abce", "abxyz", "b", "bcd", "é"] {
+                t.add(k, Some(1)).unwrap();
+            }
+            t
+        };
+        let node_count = |t: &TNode<i32>| t.depth_profile().iter().sum::<usize>();
+        for prefix in [
+            "", "a", "abc", "abcd", "abx", "abxyz", "b", "bc", "é", "x", "abcdx",
+        ] {
+            let mut t = build();
+            let estimate = t.nodes_under_prefix(prefix);
+            let before = node_count(&t);
+            t.remove(prefix, true);
+            assert_eq!(estimate, before - node_count(&t), "{}", prefix);
+        }
+        assert_eq!(build().nodes_under_prefix("abxy"), 3);
+        assert_eq!(TNode::<i32>::new().nodes_under_prefix(""), 0);
+    }
+
+    #[test]
+    fn iter_prefix() {
+        let mut t = TNode::new();
+        for k in ["", "car", "cart", "cat", "dog", "céu"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert_eq!(t.iter_prefix("car").collect::<Vec<_>>(), ["car", "cart"]);
+        assert_eq!(
+            t.iter_prefix("c").collect::<Vec<_>>(),
+            ["car", "cart", "cat", "céu"]
+        );
+        assert_eq!(t.iter_prefix("cé").collect::<Vec<_>>(), ["céu"]);
+        assert_eq!(t.iter_prefix("cx").count(), 0);
+        assert_eq!(t.iter_prefix("carts").count(), 0);
+        assert_eq!(
+            t.iter_prefix("").collect::<Vec<_>>(),
+            t.keys().collect::<Vec<_>>()
+        );
+        // the iterator does not borrow the prefix
+        let it = {
+            let prefix = String::from("ca");
+            t.iter_prefix(&prefix)
+        };
+        assert_eq!(it.collect::<Vec<_>>(), ["car", "cart", "cat"]);
+    }
+
+    #[test]
+    fn case_folded_index() {
+        let mut t = TNode::new();
+        for k in ["hello", "HELLO", "Hello", "Émile", "émile", "world"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        let index = t.case_folded_index();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index["hello"], ["HELLO", "Hello", "hello"]);
+        assert_eq!(index["émile"], ["Émile", "émile"]);
+        assert_eq!(index["world"], ["world"]);
+        assert!(!index.contains_key("HELLO"));
+    }
+
+    #[test]
+    fn values_mut_prefix() {
+        let mut t = TNode::new();
+        for (k, v) in [("", 0), ("ab", 1), ("abc", 2), ("abé", 3), ("b", 4)] {
+            t.add(k, Some(v)).unwrap();
+        }
+        t.add("abd", None).unwrap();
+        for v in t.values_mut_prefix("ab") {
+            *v += 10;
+        }
+        assert_eq!(t.values().collect::<Vec<_>>(), [&0, &11, &12, &13, &4]);
+        assert_eq!(t.values_mut_prefix("x").count(), 0);
+        assert_eq!(t.values_mut_prefix("").count(), 5);
+    }
+
+    #[test]
+    fn add_counting() {
+        let mut t = TNode::new();
+        for word in "the cat and the hat and the bat".split(' ') {
+            t.add_counting(word);
+        }
+        assert_eq!(t.get("the"), Some(&3));
+        assert_eq!(t.get("and"), Some(&2));
+        assert_eq!(t.get("cat"), Some(&1));
+        assert_eq!(t.get("th"), None);
+        t.add("dog", None).unwrap();
+        t.add_counting("dog");
+        t.add_counting("dog");
+        assert_eq!(t.get("dog"), Some(&2));
+        t.add_counting("");
+        assert_eq!(t.get(""), Some(&1));
+    }
+
+    #[test]
+    fn remove_drops_content() {
+        let shared = std::rc::Rc::new(1);
+        let mut t = TNode::new();
+        for k in ["ab", "abc"] {
+            t.add(k, Some(shared.clone())).unwrap();
+        }
+        assert!(t.remove("ab", false));
+        assert_eq!(std::rc::Rc::strong_count(&shared), 2);
+        assert_eq!(t.find("ab", false).unwrap().to_string(), "");
+        assert!(t.remove("abc", false));
+        assert_eq!(std::rc::Rc::strong_count(&shared), 1);
+    }
+
+    #[test]
+    fn cached_key_counts() {
+        fn check<T>(t: &TNode<T>) {
+            if let TNode::Node(node) = t {
+                assert_eq!(node.terminals, t.terminals().len());
+                node.children.values().for_each(check);
+            }
+        }
+        let mut t = TNode::new();
+        for k in ["", "a", "abc", "abd", "b", "bcd", "é"] {
+            t.add(k, Some(1)).unwrap();
+        }
+        assert!(t.add("abc", Some(2)).is_err());
+        check(&t);
+        t.remove("a", false);
+        t.remove("b", true);
+        t.remove("", false);
+        check(&t);
+        t.merge_sorted(["ab", "abc", "b", "bb"].map(|k| (k.to_string(), Some(2))));
+        check(&t);
+        t.add_prefix("x");
+        check(&t);
+        check(&TNode::from_sorted_strs(&["", "a", "ab", "b"]));
+
+        let keys: Vec<String> = t.keys().collect();
+        for (i, k) in keys.iter().enumerate() {
+            assert_eq!(t.rank(k), i);
+            assert_eq!(t.select(i).as_ref(), Some(k));
+        }
+    }
+
+    #[test]
+    fn shard_copies_ranges() {
+        let mut t = TNode::new();
+        for (i, k) in ["", "a", "ab", "abc", "b", "bé", "c", "cd"]
+            .iter()
+            .enumerate()
+        {
+            t.add(k, Some(i)).unwrap();
+        }
+        t.add("abcdx", None).unwrap();
+        t.remove("abcdx", false);
+        for n in 1..=10 {
+            let shards = t.shard(n);
+            let mut joined = vec![];
+            for s in &shards {
+                assert_eq!(s.key_count(), s.entries().len());
+                joined.extend(s.entries().into_iter().map(|(k, c)| (k, *c)));
+            }
+            let all: Vec<_> = t.entries().into_iter().map(|(k, c)| (k, *c)).collect();
+            assert_eq!(joined, all, "{}", n);
+            assert!(shards.iter().all(|s| s.find_dead_branches().is_empty()));
+        }
+    }
+
+    #[test]
+    fn add_tokens_rejects_separator() {
+        let mut t = TNode::new();
+        t.add_tokens(&["a", "b"], Some(1)).unwrap();
+        let joined = format!("a{}b", TOKEN_SEPARATOR);
+        assert!(matches!(
+            t.add_tokens(&[&joined], Some(2)),
+            Err(TokenAddError::SeparatorInToken)
+        ));
+        assert!(t.find_tokens(&[&joined]).is_none());
+        assert_eq!(t.find_tokens(&["a", "b"]).unwrap().content(), &Some(1));
+        assert!(matches!(
+            t.add_tokens(&["a", "b"], Some(3)),
+            Err(TokenAddError::KeyExists)
+        ));
+    }
+
+    #[test]
+    fn content_hash_is_fnv1a() {
+        let mut h = Fnv1a::default();
+        h.write(b"a");
+        assert_eq!(h.finish(), 0xaf63_dc4c_8601_ec8c);
+        let mut t = TNode::new();
+        t.add("ab", Some(7u32)).unwrap();
+        t.add("b", None).unwrap();
+        let mut h = Fnv1a::default();
+        h.write(b"ab\xff\x01\x07\x00\x00\x00b\xff\x00");
+        assert_eq!(t.content_hash(), h.finish());
+    }
+
+    #[test]
+    fn longest_prefix_shared() {
+        let mut t = TNode::new();
+        for (k, v) in [("a", "x"), ("abc", "y")] {
+            t.add(k, Some(v.to_string())).unwrap();
+        }
+        let found = t.find("a", true).unwrap();
+        assert_eq!(t.longest_prefix("abd", true), "a");
+        assert_eq!(found.content(), &Some("x".to_string()));
     }
 }